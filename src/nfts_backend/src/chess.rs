@@ -0,0 +1,720 @@
+const RESULTS: [&str; 4] = ["1-0", "0-1", "1/2-1/2", "*"];
+const START_FEN: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+type Tags = Vec<(String, String)>;
+
+pub struct Pgn {
+    pub tags: Tags,
+    pub moves: Vec<String>,
+}
+
+/// Parses a PGN game into its tag pairs and mainline moves in SAN.
+///
+/// Tag pairs must come before the movetext. Comments, NAGs and variations
+/// are skipped; only the syntax of the moves is checked here, not whether
+/// they are legal on the board.
+pub fn parse_pgn(pgn: &str) -> Result<Pgn, String> {
+    let (tags, movetext) = parse_tags(pgn)?;
+    let mut moves = Vec::new();
+    let mut result = None;
+    for token in movetext_tokens(movetext)? {
+        if let Some(result) = result {
+            return Err(format!("unexpected token after result {result}: {token}"));
+        }
+        let token = match token
+            .trim_start_matches(|c: char| c.is_ascii_digit())
+            .strip_prefix('.')
+        {
+            Some(rest) => rest.trim_start_matches('.'),
+            None => token,
+        };
+        if token.is_empty() || token.starts_with('$') {
+            continue;
+        } else if RESULTS.contains(&token) {
+            result = Some(token);
+        } else if is_san(token) {
+            moves.push(token.trim_end_matches(['!', '?']).to_owned());
+        } else {
+            return Err(format!("illegal SAN token: {token}"));
+        }
+    }
+    Ok(Pgn { tags, moves })
+}
+
+fn parse_tags(pgn: &str) -> Result<(Tags, &str), String> {
+    let mut tags = Vec::new();
+    let mut rest = pgn.trim_start();
+    while let Some(tag) = rest.strip_prefix('[') {
+        let mut in_string = false;
+        let mut escaped = false;
+        let end = tag
+            .char_indices()
+            .find(|&(_, c)| {
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = in_string;
+                } else if c == '"' {
+                    in_string = !in_string;
+                } else if c == ']' && !in_string {
+                    return true;
+                }
+                false
+            })
+            .map(|(i, _)| i)
+            .ok_or("unbalanced tag pair")?;
+        let (name, value) = tag[..end]
+            .trim()
+            .split_once(char::is_whitespace)
+            .ok_or("malformed tag pair")?;
+        let value = value.trim();
+        if name.is_empty()
+            || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            || value.len() < 2
+            || !value.starts_with('"')
+            || !value.ends_with('"')
+        {
+            return Err(format!("malformed tag pair: [{}]", &tag[..end]));
+        }
+        tags.push((
+            name.to_owned(),
+            value[1..value.len() - 1]
+                .replace("\\\"", "\"")
+                .replace("\\\\", "\\"),
+        ));
+        rest = tag[end + 1..].trim_start();
+    }
+    if rest.contains(['[', ']']) {
+        Err("unbalanced tag pair".to_owned())
+    } else {
+        Ok((tags, rest))
+    }
+}
+
+fn movetext_tokens(movetext: &str) -> Result<Vec<&str>, String> {
+    let mut tokens = Vec::new();
+    let mut depth = 0usize;
+    let mut start = None;
+    let mut chars = movetext.char_indices();
+    while let Some((i, c)) = chars.next() {
+        let boundary = c.is_whitespace() || matches!(c, '{' | '}' | '(' | ')' | ';');
+        if boundary {
+            if let Some(start) = start.take() {
+                if depth == 0 {
+                    tokens.push(&movetext[start..i]);
+                }
+            }
+        }
+        match c {
+            '{' => {
+                chars
+                    .by_ref()
+                    .find(|&(_, c)| c == '}')
+                    .ok_or("unbalanced comment")?;
+            }
+            ';' => {
+                chars.by_ref().find(|&(_, c)| c == '\n');
+            }
+            '(' => depth += 1,
+            ')' => depth = depth.checked_sub(1).ok_or("unbalanced variation")?,
+            '}' => return Err("unbalanced comment".to_owned()),
+            _ if !boundary && start.is_none() => start = Some(i),
+            _ => {}
+        }
+    }
+    if depth != 0 {
+        return Err("unbalanced variation".to_owned());
+    }
+    if let Some(start) = start {
+        tokens.push(&movetext[start..]);
+    }
+    Ok(tokens)
+}
+
+fn is_file(c: u8) -> bool {
+    (b'a'..=b'h').contains(&c)
+}
+
+fn is_rank(c: u8) -> bool {
+    (b'1'..=b'8').contains(&c)
+}
+
+/// Checks that `token` is syntactically a SAN move, allowing trailing
+/// check and annotation suffixes such as `+`, `#`, `!` and `?`.
+fn is_san(token: &str) -> bool {
+    let token = token
+        .trim_end_matches(['!', '?'])
+        .trim_end_matches(['+', '#']);
+    if token == "O-O" || token == "O-O-O" {
+        return true;
+    }
+    let (body, promotion) = match token.as_bytes() {
+        [body @ .., b'=', piece] => (body, Some(*piece)),
+        body => (body, None),
+    };
+    if promotion.is_some_and(|piece| !b"QRBN".contains(&piece)) {
+        return false;
+    }
+    match body {
+        [piece, rest @ ..] if b"KQRBN".contains(piece) => {
+            let rest = match rest {
+                [from @ .., b'x', file, rank] | [from @ .., file, rank]
+                    if is_file(*file) && is_rank(*rank) =>
+                {
+                    from
+                }
+                _ => return false,
+            };
+            promotion.is_none()
+                && match rest {
+                    [] => true,
+                    [c] => is_file(*c) || is_rank(*c),
+                    [file, rank] => is_file(*file) && is_rank(*rank),
+                    _ => false,
+                }
+        }
+        [file, rank] => is_file(*file) && is_rank(*rank),
+        [from, b'x', file, rank] => is_file(*from) && is_file(*file) && is_rank(*rank),
+        _ => false,
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Color {
+    White,
+    Black,
+}
+
+impl Color {
+    fn opponent(self) -> Color {
+        match self {
+            Color::White => Color::Black,
+            Color::Black => Color::White,
+        }
+    }
+
+    fn forward(self) -> i8 {
+        match self {
+            Color::White => 1,
+            Color::Black => -1,
+        }
+    }
+
+    fn back_rank(self) -> i8 {
+        match self {
+            Color::White => 0,
+            Color::Black => 7,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Pawn,
+    Knight,
+    Bishop,
+    Rook,
+    Queen,
+    King,
+}
+
+impl Kind {
+    fn from_char(c: char) -> Option<Kind> {
+        match c.to_ascii_uppercase() {
+            'P' => Some(Kind::Pawn),
+            'N' => Some(Kind::Knight),
+            'B' => Some(Kind::Bishop),
+            'R' => Some(Kind::Rook),
+            'Q' => Some(Kind::Queen),
+            'K' => Some(Kind::King),
+            _ => None,
+        }
+    }
+
+    fn to_char(self) -> char {
+        match self {
+            Kind::Pawn => 'P',
+            Kind::Knight => 'N',
+            Kind::Bishop => 'B',
+            Kind::Rook => 'R',
+            Kind::Queen => 'Q',
+            Kind::King => 'K',
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct Piece {
+    color: Color,
+    kind: Kind,
+}
+
+/// A square as `(file, rank)`, both zero-based from a1.
+type Square = (i8, i8);
+
+const KNIGHT_STEPS: [Square; 8] = [
+    (1, 2),
+    (2, 1),
+    (2, -1),
+    (1, -2),
+    (-1, -2),
+    (-2, -1),
+    (-2, 1),
+    (-1, 2),
+];
+const KING_STEPS: [Square; 8] = [
+    (1, 0),
+    (1, 1),
+    (0, 1),
+    (-1, 1),
+    (-1, 0),
+    (-1, -1),
+    (0, -1),
+    (1, -1),
+];
+const ROOK_DIRECTIONS: [Square; 4] = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+const BISHOP_DIRECTIONS: [Square; 4] = [(1, 1), (-1, 1), (-1, -1), (1, -1)];
+
+fn parse_square(square: &str) -> Option<Square> {
+    match square.as_bytes() {
+        [file, rank] if is_file(*file) && is_rank(*rank) => {
+            Some(((file - b'a') as i8, (rank - b'1') as i8))
+        }
+        _ => None,
+    }
+}
+
+fn format_square((file, rank): Square) -> String {
+    format!("{}{}", (b'a' + file as u8) as char, rank + 1)
+}
+
+#[derive(Clone)]
+pub struct Position {
+    board: [[Option<Piece>; 8]; 8],
+    turn: Color,
+    /// White king side, white queen side, black king side, black queen side.
+    castling: [bool; 4],
+    en_passant: Option<Square>,
+    halfmove: u32,
+    fullmove: u32,
+}
+
+impl Position {
+    /// Parses and validates a FEN string: six space-separated fields, eight
+    /// ranks that each sum to eight files, and well-formed side to move,
+    /// castling, en passant and clock fields.
+    pub fn from_fen(fen: &str) -> Result<Position, String> {
+        let fields: Vec<&str> = fen.split(' ').collect();
+        let [placement, turn, castling, en_passant, halfmove, fullmove] = fields[..] else {
+            return Err(format!(
+                "fen must have 6 space-separated fields, found {}",
+                fields.len()
+            ));
+        };
+        let ranks: Vec<&str> = placement.split('/').collect();
+        if ranks.len() != 8 {
+            return Err(format!("fen must have 8 ranks, found {}", ranks.len()));
+        }
+        let mut board = [[None; 8]; 8];
+        for (i, rank) in ranks.iter().enumerate() {
+            let rank_index = 7 - i;
+            let mut file = 0;
+            for c in rank.chars() {
+                if let Some(empty) = c.to_digit(10).filter(|n| (1..=8).contains(n)) {
+                    file += empty as usize;
+                } else if let Some(kind) = Kind::from_char(c) {
+                    if file < 8 {
+                        let color = if c.is_ascii_uppercase() {
+                            Color::White
+                        } else {
+                            Color::Black
+                        };
+                        board[rank_index][file] = Some(Piece { color, kind });
+                    }
+                    file += 1;
+                } else {
+                    return Err(format!("invalid piece {c:?} in rank {}", rank_index + 1));
+                }
+            }
+            if file != 8 {
+                return Err(format!(
+                    "rank {} sums to {file} files instead of 8",
+                    rank_index + 1
+                ));
+            }
+        }
+        let turn = match turn {
+            "w" => Color::White,
+            "b" => Color::Black,
+            _ => return Err(format!("invalid side to move: {turn}")),
+        };
+        let mut rights = [false; 4];
+        if castling != "-" {
+            for c in castling.chars() {
+                let index = "KQkq"
+                    .find(c)
+                    .ok_or_else(|| format!("invalid castling availability: {castling}"))?;
+                if rights[index] {
+                    return Err(format!("invalid castling availability: {castling}"));
+                }
+                rights[index] = true;
+            }
+        }
+        let en_passant = match en_passant {
+            "-" => None,
+            square => match parse_square(square) {
+                Some(square) if square.1 == 2 || square.1 == 5 => Some(square),
+                _ => return Err(format!("invalid en passant square: {square}")),
+            },
+        };
+        let halfmove = halfmove
+            .parse()
+            .map_err(|_| format!("invalid halfmove clock: {halfmove}"))?;
+        let fullmove = fullmove
+            .parse()
+            .ok()
+            .filter(|&n: &u32| n > 0)
+            .ok_or_else(|| format!("invalid fullmove number: {fullmove}"))?;
+        Ok(Position {
+            board,
+            turn,
+            castling: rights,
+            en_passant,
+            halfmove,
+            fullmove,
+        })
+    }
+
+    pub fn to_fen(&self) -> String {
+        let mut placement = String::new();
+        for rank in self.board.iter().rev() {
+            let mut empty = 0;
+            for square in rank {
+                match square {
+                    Some(piece) => {
+                        if empty > 0 {
+                            placement.push_str(&empty.to_string());
+                            empty = 0;
+                        }
+                        let c = piece.kind.to_char();
+                        placement.push(match piece.color {
+                            Color::White => c,
+                            Color::Black => c.to_ascii_lowercase(),
+                        });
+                    }
+                    None => empty += 1,
+                }
+            }
+            if empty > 0 {
+                placement.push_str(&empty.to_string());
+            }
+            placement.push('/');
+        }
+        placement.pop();
+        let castling: String = "KQkq"
+            .chars()
+            .zip(self.castling)
+            .filter_map(|(c, right)| right.then_some(c))
+            .collect();
+        format!(
+            "{placement} {} {} {} {} {}",
+            match self.turn {
+                Color::White => 'w',
+                Color::Black => 'b',
+            },
+            if castling.is_empty() { "-" } else { &castling },
+            self.en_passant
+                .map(format_square)
+                .unwrap_or_else(|| "-".to_owned()),
+            self.halfmove,
+            self.fullmove,
+        )
+    }
+
+    fn at(&self, (file, rank): Square) -> Option<Piece> {
+        self.board[rank as usize][file as usize]
+    }
+
+    fn set(&mut self, (file, rank): Square, piece: Option<Piece>) {
+        self.board[rank as usize][file as usize] = piece
+    }
+
+    fn on_board((file, rank): Square) -> bool {
+        (0..8).contains(&file) && (0..8).contains(&rank)
+    }
+
+    fn path_clear(&self, from: Square, to: Square) -> bool {
+        let step = ((to.0 - from.0).signum(), (to.1 - from.1).signum());
+        let mut square = (from.0 + step.0, from.1 + step.1);
+        while square != to {
+            if self.at(square).is_some() {
+                return false;
+            }
+            square = (square.0 + step.0, square.1 + step.1);
+        }
+        true
+    }
+
+    /// Whether the piece on `from` could move to `to` ignoring checks.
+    fn can_reach(&self, from: Square, to: Square) -> bool {
+        let Some(piece) = self.at(from) else {
+            return false;
+        };
+        if self
+            .at(to)
+            .is_some_and(|target| target.color == piece.color)
+        {
+            return false;
+        }
+        let (df, dr) = (to.0 - from.0, to.1 - from.1);
+        match piece.kind {
+            Kind::Knight => KNIGHT_STEPS.contains(&(df, dr)),
+            Kind::King => KING_STEPS.contains(&(df, dr)),
+            Kind::Rook => (df == 0) != (dr == 0) && self.path_clear(from, to),
+            Kind::Bishop => df != 0 && df.abs() == dr.abs() && self.path_clear(from, to),
+            Kind::Queen => {
+                ((df == 0) != (dr == 0) || (df != 0 && df.abs() == dr.abs()))
+                    && self.path_clear(from, to)
+            }
+            Kind::Pawn => {
+                let forward = piece.color.forward();
+                if df == 0 {
+                    self.at(to).is_none()
+                        && (dr == forward
+                            || (dr == 2 * forward
+                                && from.1 == piece.color.back_rank() + forward
+                                && self.path_clear(from, to)))
+                } else {
+                    df.abs() == 1
+                        && dr == forward
+                        && (self.at(to).is_some() || self.en_passant == Some(to))
+                }
+            }
+        }
+    }
+
+    fn is_attacked(&self, square: Square, by: Color) -> bool {
+        let attacker = |step: &Square, kinds: &[Kind]| {
+            let from = (square.0 + step.0, square.1 + step.1);
+            Self::on_board(from)
+                && self
+                    .at(from)
+                    .is_some_and(|piece| piece.color == by && kinds.contains(&piece.kind))
+        };
+        let slider = |direction: &Square, kinds: &[Kind]| {
+            let mut from = (square.0 + direction.0, square.1 + direction.1);
+            while Self::on_board(from) {
+                if let Some(piece) = self.at(from) {
+                    return piece.color == by && kinds.contains(&piece.kind);
+                }
+                from = (from.0 + direction.0, from.1 + direction.1);
+            }
+            false
+        };
+        KNIGHT_STEPS
+            .iter()
+            .any(|step| attacker(step, &[Kind::Knight]))
+            || KING_STEPS.iter().any(|step| attacker(step, &[Kind::King]))
+            || [(-1, -by.forward()), (1, -by.forward())]
+                .iter()
+                .any(|step| attacker(step, &[Kind::Pawn]))
+            || ROOK_DIRECTIONS
+                .iter()
+                .any(|direction| slider(direction, &[Kind::Rook, Kind::Queen]))
+            || BISHOP_DIRECTIONS
+                .iter()
+                .any(|direction| slider(direction, &[Kind::Bishop, Kind::Queen]))
+    }
+
+    fn in_check(&self, color: Color) -> bool {
+        (0..8)
+            .flat_map(|rank| (0..8).map(move |file| (file, rank)))
+            .find(|&square| {
+                self.at(square)
+                    == Some(Piece {
+                        color,
+                        kind: Kind::King,
+                    })
+            })
+            .is_some_and(|king| self.is_attacked(king, color.opponent()))
+    }
+
+    fn apply(&mut self, from: Square, to: Square, promotion: Option<Kind>) {
+        let piece = self.at(from).expect("moving from an empty square");
+        let mut capture = self.at(to).is_some();
+        if piece.kind == Kind::Pawn && self.en_passant == Some(to) {
+            self.set((to.0, from.1), None);
+            capture = true;
+        }
+        if piece.kind == Kind::King && (to.0 - from.0).abs() == 2 {
+            let (rook_from, rook_to) = if to.0 > from.0 {
+                ((7, from.1), (5, from.1))
+            } else {
+                ((0, from.1), (3, from.1))
+            };
+            self.set(rook_to, self.at(rook_from));
+            self.set(rook_from, None);
+        }
+        self.set(from, None);
+        self.set(
+            to,
+            Some(Piece {
+                color: piece.color,
+                kind: promotion.unwrap_or(piece.kind),
+            }),
+        );
+
+        for (index, corner) in [(0, (7, 0)), (1, (0, 0)), (2, (7, 7)), (3, (0, 7))] {
+            if from == corner || to == corner {
+                self.castling[index] = false;
+            }
+        }
+        if piece.kind == Kind::King {
+            let offset = if piece.color == Color::White { 0 } else { 2 };
+            self.castling[offset] = false;
+            self.castling[offset + 1] = false;
+        }
+        self.en_passant = (piece.kind == Kind::Pawn && (to.1 - from.1).abs() == 2)
+            .then_some((from.0, (from.1 + to.1) / 2));
+        if piece.kind == Kind::Pawn || capture {
+            self.halfmove = 0;
+        } else {
+            self.halfmove += 1;
+        }
+        if self.turn == Color::Black {
+            self.fullmove += 1;
+        }
+        self.turn = self.turn.opponent();
+    }
+
+    fn castle(&mut self, king_side: bool) -> Result<(), String> {
+        let rank = self.turn.back_rank();
+        let right = if self.turn == Color::White { 0 } else { 2 } + usize::from(!king_side);
+        let (rook_file, king_path): (i8, &[i8]) = if king_side {
+            (7, &[5, 6])
+        } else {
+            (0, &[3, 2])
+        };
+        let between: &[i8] = if king_side { &[5, 6] } else { &[1, 2, 3] };
+        if !self.castling[right]
+            || self.at((4, rank))
+                != Some(Piece {
+                    color: self.turn,
+                    kind: Kind::King,
+                })
+            || self.at((rook_file, rank))
+                != Some(Piece {
+                    color: self.turn,
+                    kind: Kind::Rook,
+                })
+            || between.iter().any(|&file| self.at((file, rank)).is_some())
+            || std::iter::once(&4)
+                .chain(king_path)
+                .any(|&file| self.is_attacked((file, rank), self.turn.opponent()))
+        {
+            return Err("illegal castling".to_owned());
+        }
+        self.apply((4, rank), (king_path[1], rank), None);
+        Ok(())
+    }
+
+    /// Plays one SAN move, rejecting it if it is illegal or ambiguous.
+    pub fn play_san(&mut self, san: &str) -> Result<(), String> {
+        let san = san
+            .trim_end_matches(['!', '?'])
+            .trim_end_matches(['+', '#']);
+        match san {
+            "O-O" => return self.castle(true),
+            "O-O-O" => return self.castle(false),
+            _ => {}
+        }
+        let (body, promotion) = match san.split_once('=') {
+            Some((body, piece)) => (
+                body,
+                Some(
+                    Kind::from_char(piece.chars().next().unwrap_or_default())
+                        .filter(|kind| ![Kind::Pawn, Kind::King].contains(kind))
+                        .ok_or_else(|| format!("invalid promotion: {san}"))?,
+                ),
+            ),
+            None => (san, None),
+        };
+        let (kind, body) = match body.chars().next().and_then(Kind::from_char) {
+            Some(kind) if body.starts_with(|c: char| c.is_ascii_uppercase()) => (kind, &body[1..]),
+            _ => (Kind::Pawn, body),
+        };
+        if body.len() < 2 {
+            return Err(format!("invalid move: {san}"));
+        }
+        let (from_hint, to) = body.split_at(body.len() - 2);
+        let to = parse_square(to).ok_or_else(|| format!("invalid move: {san}"))?;
+        let capture = from_hint.ends_with('x');
+        let from_hint = from_hint.trim_end_matches('x').as_bytes();
+        let from_file = from_hint
+            .iter()
+            .find(|c| is_file(**c))
+            .map(|c| (c - b'a') as i8);
+        let from_rank = from_hint
+            .iter()
+            .find(|c| is_rank(**c))
+            .map(|c| (c - b'1') as i8);
+
+        let is_capture =
+            self.at(to).is_some() || (kind == Kind::Pawn && self.en_passant == Some(to));
+        if capture != is_capture {
+            return Err(format!("capture mismatch in move: {san}"));
+        }
+        let last_rank = to.1 == self.turn.opponent().back_rank();
+        if (kind == Kind::Pawn && last_rank) != promotion.is_some() {
+            return Err(format!("invalid promotion in move: {san}"));
+        }
+
+        let candidates: Vec<Square> = (0..8)
+            .flat_map(|rank| (0..8).map(move |file| (file, rank)))
+            .filter(|&from| {
+                self.at(from)
+                    == Some(Piece {
+                        color: self.turn,
+                        kind,
+                    })
+                    && from_file.is_none_or(|file| from.0 == file)
+                    && from_rank.is_none_or(|rank| from.1 == rank)
+                    && self.can_reach(from, to)
+                    && {
+                        let mut next = self.clone();
+                        next.apply(from, to, promotion);
+                        !next.in_check(self.turn)
+                    }
+            })
+            .collect();
+        match candidates[..] {
+            [from] => {
+                self.apply(from, to, promotion);
+                Ok(())
+            }
+            [] => Err(format!("illegal move: {san}")),
+            _ => Err(format!("ambiguous move: {san}")),
+        }
+    }
+
+    /// Compares piece placement, side to move and castling rights, ignoring
+    /// the en passant square and the move clocks.
+    pub fn same_position(&self, other: &Position) -> bool {
+        self.board == other.board && self.turn == other.turn && self.castling == other.castling
+    }
+}
+
+/// Replays a PGN game from its starting position (the `FEN` tag when
+/// present) and returns the final position.
+pub fn replay(pgn: &Pgn) -> Result<Position, String> {
+    let mut position = match pgn.tags.iter().find(|(name, _)| name == "FEN") {
+        Some((_, fen)) => Position::from_fen(fen)?,
+        None => Position::from_fen(START_FEN)?,
+    };
+    for (i, san) in pgn.moves.iter().enumerate() {
+        position
+            .play_san(san)
+            .map_err(|error| format!("move {}: {error}", i + 1))?;
+    }
+    Ok(position)
+}
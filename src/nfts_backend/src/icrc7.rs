@@ -0,0 +1,82 @@
+//! ICRC-7 read-only view over the collection with the lowest id, so that
+//! wallets speaking the standard see a canister holding a single collection.
+
+use candid::{CandidType, Principal};
+use ic_cdk::query;
+use serde::Deserialize;
+
+use crate::{nft_key, Collection, NFTS, STATE};
+
+#[derive(CandidType, Deserialize)]
+pub struct Account {
+    owner: Principal,
+    subaccount: Option<Vec<u8>>,
+}
+
+impl Account {
+    /// Tokens are held by principals only, which ICRC-7 expresses as the
+    /// default (absent or all-zero) subaccount.
+    fn principal(&self) -> Option<Principal> {
+        match &self.subaccount {
+            Some(subaccount) if subaccount.iter().any(|&byte| byte != 0) => None,
+            _ => Some(self.owner),
+        }
+    }
+}
+
+fn with_collection<T>(f: impl FnOnce(usize, &Collection) -> T) -> Option<T> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        state
+            .collections
+            .iter()
+            .min_by_key(|(&id, _)| id)
+            .map(|(&id, collection)| f(id, collection))
+    })
+}
+
+#[query]
+fn icrc7_name() -> String {
+    with_collection(|_, collection| collection.name.clone()).unwrap_or_default()
+}
+
+#[query]
+fn icrc7_symbol() -> String {
+    with_collection(|_, collection| collection.symbol.clone()).unwrap_or_default()
+}
+
+#[query]
+fn icrc7_total_supply() -> u128 {
+    with_collection(|_, collection| collection.live_supply().into()).unwrap_or_default()
+}
+
+#[query]
+fn icrc7_owner_of(token_ids: Vec<u128>) -> Vec<Option<Account>> {
+    let collection_id = with_collection(|id, _| id);
+    token_ids
+        .into_iter()
+        .map(|token_id| {
+            let key = nft_key(collection_id?, u64::try_from(token_id).ok()?);
+            NFTS.with(|nfts| nfts.borrow().get(&key))
+                .map(|nft| Account {
+                    owner: nft.owner,
+                    subaccount: None,
+                })
+        })
+        .collect()
+}
+
+#[query]
+fn icrc7_balance_of(accounts: Vec<Account>) -> Vec<u128> {
+    accounts
+        .into_iter()
+        .map(|account| {
+            account
+                .principal()
+                .and_then(|principal| {
+                    with_collection(|_, collection| collection.balance_of(&principal) as u128)
+                })
+                .unwrap_or_default()
+        })
+        .collect()
+}
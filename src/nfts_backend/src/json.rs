@@ -0,0 +1,95 @@
+//! JSON rendering of token metadata for viewers that do not speak Candid.
+//! Object keys are written in sorted order, so the same metadata always
+//! produces the same bytes.
+
+use crate::{MetadataPart, MetadataPurpose, MetadataValue};
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// `{"collection_id":..,"metadata":[..],"token_id":..}`, with each part as
+/// `{"data":..,"key_val_data":{..},"purpose":..}`. Blobs and part data are
+/// base64 strings, naturals are JSON numbers.
+pub fn token_metadata(collection_id: usize, token_id: u64, metadata: &[MetadataPart]) -> String {
+    let mut out = format!("{{\"collection_id\":{collection_id},\"metadata\":[");
+    for (i, part) in metadata.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        push_part(&mut out, part);
+    }
+    out.push_str(&format!("],\"token_id\":{token_id}}}"));
+    out
+}
+
+fn push_part(out: &mut String, part: &MetadataPart) {
+    out.push_str("{\"data\":");
+    push_string(out, &base64(&part.data));
+    out.push_str(",\"key_val_data\":{");
+    let mut entries: Vec<_> = part.key_val_data.iter().collect();
+    entries.sort_unstable_by_key(|&(key, _)| key);
+    for (i, (key, value)) in entries.into_iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        push_string(out, key);
+        out.push(':');
+        push_value(out, value);
+    }
+    out.push_str("},\"purpose\":");
+    push_string(
+        out,
+        match part.purpose {
+            MetadataPurpose::Preview => "Preview",
+            MetadataPurpose::Rendered => "Rendered",
+        },
+    );
+    out.push('}');
+}
+
+fn push_value(out: &mut String, value: &MetadataValue) {
+    match value {
+        MetadataValue::Text(text) => push_string(out, text),
+        MetadataValue::Blob(blob) => push_string(out, &base64(blob)),
+        MetadataValue::Nat8(n) => out.push_str(&n.to_string()),
+        MetadataValue::Nat16(n) => out.push_str(&n.to_string()),
+        MetadataValue::Nat32(n) => out.push_str(&n.to_string()),
+        MetadataValue::Nat64(n) => out.push_str(&n.to_string()),
+        MetadataValue::Nat(n) => out.push_str(&n.to_string()),
+    }
+}
+
+fn push_string(out: &mut String, text: &str) {
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Standard alphabet, padded.
+fn base64(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &byte)| n | (u32::from(byte) << (16 - 8 * i)));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[((n >> (18 - 6 * i)) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
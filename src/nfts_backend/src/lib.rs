@@ -1,69 +1,1130 @@
+use std::borrow::Cow;
 use std::cell::RefCell;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::collections::VecDeque;
 
 use candid::{CandidType, Principal};
-use ic_cdk::{api, post_upgrade, pre_upgrade, query, storage, update};
+use ic_cdk::{api, init, post_upgrade, pre_upgrade, query, update};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::writer::Writer;
+use ic_stable_structures::{DefaultMemoryImpl, Memory as _, StableBTreeMap, StableVec, Storable};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+mod chess;
+mod icrc7;
+mod json;
+mod runtime;
 
 const ANONYMOUS: Principal = Principal::anonymous();
+const MAX_LIMIT: usize = 1000;
+/// Ledger entries a filtered transaction query looks at per call.
+const MAX_TRANSACTION_SCAN: u64 = 10_000;
+const MAX_BATCH_LEN: usize = 100;
+const MAX_MEMO_LEN: usize = 32;
+const MAX_REASON_LEN: usize = 256;
+const MAX_SHIPPING_HASH_LEN: usize = 64;
+const MAX_NOTE_LEN: usize = 1024;
+const MAX_DESCRIPTION_LEN: usize = 4096;
+/// Room for a transaction with the longest memo and reason allowed.
+const MAX_TRANSACTION_SIZE: u32 = 1024;
+const MAX_ROYALTY_BPS: u16 = 10_000;
+const MAX_UPLOAD_SIZE: usize = 32 * 1024 * 1024;
+const UPLOAD_TTL_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const PENDING_TRANSFER_TTL_NANOS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+const IDEMPOTENCY_TTL_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const MAX_EVENTS: usize = 100_000;
+const DEFAULT_MAX_LOGO_SIZE: usize = 256 * 1024;
+/// Collections minting larger tokens through uploads raise their own limit.
+const DEFAULT_MAX_CONTENT_BYTES: u64 = 2 * 1024 * 1024;
+const MAX_NAME_LEN: usize = 128;
+const MAX_SYMBOL_LEN: usize = 16;
+const MAX_TAGS: usize = 16;
+const MAX_TAG_LEN: usize = 32;
+const MAX_REQUIRED_KEYS: usize = 32;
+const MAX_TRANSFER_HOOKS: usize = 8;
+const MAX_METHOD_NAME_LEN: usize = 64;
+/// Rarity scores are fixed point with six decimal places.
+const RARITY_SCALE: u64 = 1_000_000;
+const MAX_SUBSCRIBERS: usize = 16;
+const MAX_PENDING_DELIVERIES: usize = 1000;
+const MAX_DELIVERY_ATTEMPTS: u8 = 3;
+
+const UPGRADES_MEMORY: MemoryId = MemoryId::new(0);
+const NFTS_MEMORY: MemoryId = MemoryId::new(1);
+const TRANSACTIONS_MEMORY: MemoryId = MemoryId::new(2);
 
+type Memory = VirtualMemory<DefaultMemoryImpl>;
 type Collections = HashMap<usize, Collection>;
-type Nfts = HashMap<u64, Nft>;
+type Nfts = StableBTreeMap<(u64, u64), Nft, Memory>;
+type Transactions = StableVec<Transaction, Memory>;
 type Custodians = HashSet<Principal>;
 type Operators = HashMap<Principal, HashSet<Principal>>;
+type OwnerIndex = HashMap<Principal, HashSet<u64>>;
+type Roles = HashMap<Principal, HashSet<Role>>;
 
 thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
     static STATE: RefCell<State> = RefCell::default();
+
+    static NFTS: RefCell<Nfts> = RefCell::new(Nfts::init(
+        MEMORY_MANAGER.with(|manager| manager.borrow().get(NFTS_MEMORY)),
+    ));
+
+    static TRANSACTIONS: RefCell<Transactions> = RefCell::new(
+        Transactions::init(MEMORY_MANAGER.with(|manager| manager.borrow().get(TRANSACTIONS_MEMORY)))
+            .expect("failed to init transactions"),
+    );
+}
+
+fn nft_key(collection_id: usize, token_id: u64) -> (u64, u64) {
+    (collection_id as u64, token_id)
+}
+
+fn nfts_of_collection(nfts: &Nfts, collection_id: usize) -> impl Iterator<Item = Nft> + '_ {
+    nfts.range(nft_key(collection_id, 0)..=nft_key(collection_id, u64::MAX))
+        .map(|(_, nft)| nft)
 }
 
-#[derive(Default, Clone, Serialize, Deserialize)]
+#[derive(Default, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
 pub struct State {
     collections: Collections,
+    collection_id: usize,
     txid: u128,
+    /// The ledger as saved before it moved to `TRANSACTIONS`, emptied on
+    /// restore.
+    #[serde(skip_serializing)]
+    transactions: Vec<Transaction>,
+    proposals: HashMap<u64, Proposal>,
+    proposal_id: u64,
+    uploads: HashMap<u64, Upload>,
+    upload_id: u64,
+    events: VecDeque<Event>,
+    event_seq: u64,
+    op_counts: HashMap<String, u64>,
+    pending_transfers: HashMap<u64, PendingTransfer>,
+    pending_transfer_id: u64,
+    /// Cycles attached to `insert_collection` and kept by the canister.
+    creation_fee: u64,
+    redemptions: Vec<Redemption>,
+    idempotency_keys: HashMap<(Principal, [u8; 32]), IdempotentCall>,
+    subscribers: Vec<Subscriber>,
+    /// Deliveries the system refused to enqueue, retried on the next event.
+    pending_deliveries: VecDeque<Delivery>,
+    /// Owners' notes on their tokens, by nft key. Kept out of `Nft` so no
+    /// token query returns them.
+    notes: HashMap<(u64, u64), String>,
+    /// Nft keys of the tokens each proposal has counted, so a token moved to
+    /// another principal cannot vote twice.
+    voted_tokens: HashMap<u64, HashSet<(u64, u64)>>,
+    /// When the running code was installed or last upgraded.
+    #[serde(skip)]
+    upgraded_at: u64,
 }
 
+/// Ids handed out by the counters below are never reused, so a counter that
+/// runs out traps the call rather than wrapping around onto an id that is
+/// already taken. None of them can realistically get there.
 impl State {
+    pub fn next_collection_id(&mut self) -> usize {
+        self.collection_id = self
+            .collection_id
+            .checked_add(1)
+            .expect("collection id counter overflowed");
+        self.collection_id
+    }
+
     pub fn next_txid(&mut self) -> u128 {
-        self.txid += 1;
+        self.txid = self.txid.checked_add(1).expect("txid counter overflowed");
         self.txid
     }
+
+    pub fn next_proposal_id(&mut self) -> u64 {
+        self.proposal_id = self
+            .proposal_id
+            .checked_add(1)
+            .expect("proposal id counter overflowed");
+        self.proposal_id
+    }
+
+    pub fn next_upload_id(&mut self) -> u64 {
+        self.upload_id = self
+            .upload_id
+            .checked_add(1)
+            .expect("upload id counter overflowed");
+        self.upload_id
+    }
+
+    pub fn next_pending_transfer_id(&mut self) -> u64 {
+        self.pending_transfer_id = self
+            .pending_transfer_id
+            .checked_add(1)
+            .expect("pending transfer id counter overflowed");
+        self.pending_transfer_id
+    }
+
+    /// Prunes expired keys, then returns what the caller's first call with
+    /// `key` produced, if there was one.
+    pub fn replay(
+        &mut self,
+        caller: Principal,
+        key: Option<[u8; 32]>,
+        op: TxOp,
+    ) -> Result<Option<u128>, NftError> {
+        let Some(key) = key else {
+            return Ok(None);
+        };
+        let now = runtime::time();
+        self.idempotency_keys
+            .retain(|_, call| now < call.expires_at);
+        match self.idempotency_keys.get(&(caller, key)) {
+            Some(call) if call.op != op => Err(NftError::Other(
+                "idempotency key was used for another operation".to_owned(),
+            )),
+            Some(call) => Ok(Some(call.result)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn remember(&mut self, caller: Principal, key: Option<[u8; 32]>, op: TxOp, result: u128) {
+        if let Some(key) = key {
+            let expires_at = runtime::time().saturating_add(IDEMPOTENCY_TTL_NANOS);
+            self.idempotency_keys.insert(
+                (caller, key),
+                IdempotentCall {
+                    op,
+                    result,
+                    expires_at,
+                },
+            );
+        }
+    }
+
+    pub fn next_event_seq(&mut self) -> u64 {
+        self.event_seq = self
+            .event_seq
+            .checked_add(1)
+            .expect("event seq counter overflowed");
+        self.event_seq
+    }
+
+    pub fn emit(&mut self, kind: EventKind) -> u64 {
+        let seq = self.next_event_seq();
+        let event = Event {
+            seq,
+            kind,
+            timestamp: runtime::time(),
+        };
+        self.deliver(&event);
+        self.events.push_back(event);
+        if self.events.len() > MAX_EVENTS {
+            self.events.pop_front();
+        }
+        seq
+    }
+
+    /// Pushes `event` to every subscriber whose filter matches, after
+    /// retrying earlier failed deliveries. Deliveries are one-way calls, so
+    /// only a call the system refuses to enqueue is noticed and retried; a
+    /// subscriber that rejects or traps misses the event and can catch up
+    /// with `poll_events`.
+    fn deliver(&mut self, event: &Event) {
+        let mut deliveries: Vec<Delivery> = self.pending_deliveries.drain(..).collect();
+        deliveries.extend(
+            self.subscribers
+                .iter()
+                .filter(|subscriber| subscriber.filter.matches(&event.kind))
+                .map(|subscriber| Delivery {
+                    canister: subscriber.canister,
+                    method: subscriber.method.clone(),
+                    event: event.clone(),
+                    attempts: 0,
+                }),
+        );
+        for mut delivery in deliveries {
+            let sent = runtime::notify(
+                delivery.canister,
+                &delivery.method,
+                (delivery.event.clone(),),
+            );
+            delivery.attempts += 1;
+            if sent.is_err() && delivery.attempts < MAX_DELIVERY_ATTEMPTS {
+                self.pending_deliveries.push_back(delivery);
+                if self.pending_deliveries.len() > MAX_PENDING_DELIVERIES {
+                    self.pending_deliveries.pop_front();
+                }
+            }
+        }
+    }
+
+    pub fn record(
+        &mut self,
+        op: TxOp,
+        collection_id: usize,
+        token_id: Option<u64>,
+        from: Option<Principal>,
+        to: Option<Principal>,
+    ) -> u128 {
+        self.log(Transaction {
+            id: 0,
+            op,
+            collection_id,
+            token_id,
+            from,
+            to,
+            memo: None,
+            reason: None,
+            fee: None,
+            caller: None,
+            timestamp: 0,
+        })
+    }
+
+    /// Appends `transaction` to the ledger under a fresh txid, stamped with
+    /// the current caller and time, overwriting whatever it carried there.
+    pub fn log(&mut self, mut transaction: Transaction) -> u128 {
+        let id = self.next_txid();
+        transaction.id = id;
+        transaction.caller = Some(runtime::caller());
+        transaction.timestamp = runtime::time();
+        *self
+            .op_counts
+            .entry(transaction.op.name().to_owned())
+            .or_default() += 1;
+        self.emit(EventKind::Transaction(Box::new(transaction.clone())));
+        TRANSACTIONS.with(|transactions| {
+            transactions
+                .borrow()
+                .push(&transaction)
+                .expect("failed to grow transactions")
+        });
+        id
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, PartialEq)]
+pub enum TxOp {
+    Mint,
+    Transfer,
+    Approve,
+    Burn,
+    ApprovalForAll,
+    AdminTransfer,
+    Offer,
+}
+
+impl TxOp {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TxOp::Mint => "Mint",
+            TxOp::Transfer => "Transfer",
+            TxOp::Approve => "Approve",
+            TxOp::Burn => "Burn",
+            TxOp::ApprovalForAll => "ApprovalForAll",
+            TxOp::AdminTransfer => "AdminTransfer",
+            TxOp::Offer => "Offer",
+        }
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Transaction {
+    id: u128,
+    op: TxOp,
+    collection_id: usize,
+    token_id: Option<u64>,
+    from: Option<Principal>,
+    to: Option<Principal>,
+    memo: Option<Vec<u8>>,
+    reason: Option<String>,
+    /// Fee owed to the collection treasury, settled out-of-band.
+    fee: Option<u128>,
+    /// Who made the call, which differs from `from` when e.g. a custodian
+    /// burns someone else's token.
+    caller: Option<Principal>,
+    timestamp: u64,
+}
+
+/// Only the last `MAX_EVENTS` events are kept. `seq` starts at 1 and never
+/// wraps or repeats, so an indexer that polls with a seq older than the
+/// oldest retained event gets a first event with `seq > after_seq + 1` and
+/// knows it has missed the events in between.
+#[derive(CandidType, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Event {
+    seq: u64,
+    kind: EventKind,
+    timestamp: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, PartialEq)]
+pub enum EventKind {
+    /// Carries the transaction, and with it the txid, that produced the event.
+    Transaction(Box<Transaction>),
+    ProposalCreated(u64),
+    /// The call to a registered transfer hook could not be sent.
+    TransferHookFailed {
+        collection_id: usize,
+        token_id: u64,
+        hook: TransferHook,
+        error: String,
+    },
+    /// A collection's whole custodian set was handed off in one call.
+    CustodiansReplaced {
+        collection_id: usize,
+        old: Vec<Principal>,
+        new: Vec<Principal>,
+    },
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Proposal {
+    id: u64,
+    description: String,
+    created: u64,
+    deadline: u64,
+    yes: u128,
+    no: u128,
+    voters: HashSet<Principal>,
+    #[serde(default)]
+    quorum_bps: u16,
+    #[serde(default = "Proposal::majority_bps")]
+    threshold_bps: u16,
+    /// Voting weight in existence when the proposal was created.
+    #[serde(default)]
+    eligible_supply: u128,
+}
+
+impl Proposal {
+    /// Proposals created before thresholds existed pass on a simple majority.
+    fn majority_bps() -> u16 {
+        5_000
+    }
+
+    pub fn result(&self) -> ProposalResult {
+        let total = self.yes + self.no;
+        if runtime::time() <= self.deadline {
+            ProposalResult::Pending
+        } else if total * 10_000 < self.eligible_supply * u128::from(self.quorum_bps) {
+            ProposalResult::QuorumNotMet
+        } else if self.yes * 10_000 > total * u128::from(self.threshold_bps) {
+            ProposalResult::Passed
+        } else {
+            ProposalResult::Rejected
+        }
+    }
+}
+
+#[derive(CandidType, PartialEq, Debug)]
+pub enum ProposalResult {
+    Pending,
+    Passed,
+    Rejected,
+    QuorumNotMet,
+}
+
+/// A canister method called with `(collection_id, token_id, from, to)` after
+/// every `transfer_from_to` in the collection.
+#[derive(CandidType, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TransferHook {
+    canister: Principal,
+    method: String,
+}
+
+/// The outcome of a keyed call: the token id for a mint, the txid for a
+/// transfer. Only successful calls are remembered, so a failed one can be
+/// retried with the same key.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct IdempotentCall {
+    op: TxOp,
+    result: u128,
+    expires_at: u64,
+}
+
+/// A custodian transfer waiting for the collection's required number of
+/// custodian approvals. The proposer's approval counts.
+#[derive(CandidType, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PendingTransfer {
+    id: u64,
+    collection_id: usize,
+    token_id: u64,
+    from: Principal,
+    to: Principal,
+    approvals: HashSet<Principal>,
+    expires_at: u64,
+}
+
+/// Narrows the events pushed to a subscriber. Events other than
+/// transactions only pass a filter that names no ops.
+#[derive(CandidType, Serialize, Deserialize, Clone, PartialEq)]
+pub struct EventFilter {
+    collection_id: Option<usize>,
+    /// Transaction ops to deliver; empty delivers every event.
+    ops: Vec<TxOp>,
+}
+
+impl EventFilter {
+    pub fn matches(&self, kind: &EventKind) -> bool {
+        let (collection_id, op) = match kind {
+            EventKind::Transaction(tx) => (Some(tx.collection_id), Some(&tx.op)),
+            EventKind::ProposalCreated(_) => (None, None),
+            EventKind::TransferHookFailed { collection_id, .. }
+            | EventKind::CustodiansReplaced { collection_id, .. } => (Some(*collection_id), None),
+        };
+        (self.collection_id.is_none() || self.collection_id == collection_id)
+            && (self.ops.is_empty() || op.is_some_and(|op| self.ops.contains(op)))
+    }
+}
+
+/// A canister method called with each matching `Event`.
+#[derive(CandidType, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Subscriber {
+    canister: Principal,
+    method: String,
+    filter: EventFilter,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct Delivery {
+    canister: Principal,
+    method: String,
+    event: Event,
+    attempts: u8,
+}
+
+/// A claim on a physical prize, made by burning the token it came with.
+/// `shipping_hash` commits to delivery details kept off-chain.
+#[derive(CandidType, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Redemption {
+    collection_id: usize,
+    token_id: u64,
+    burner: Principal,
+    shipping_hash: Vec<u8>,
+    txid: u128,
+    time: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct Upload {
+    collection_id: usize,
+    uploader: Principal,
+    content: Vec<u8>,
+    created: u64,
 }
 
-#[derive(CandidType, Serialize, Deserialize, Clone, Default)]
+#[derive(CandidType, Serialize, Deserialize, Clone, Default, PartialEq)]
 pub enum LogoExtension {
     #[default]
     Png,
     Jpg,
     Jpeg,
+    Svg,
+    Gif,
+    Webp,
+}
+
+impl LogoExtension {
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            LogoExtension::Png => "image/png",
+            LogoExtension::Jpg | LogoExtension::Jpeg => "image/jpeg",
+            LogoExtension::Svg => "image/svg+xml",
+            LogoExtension::Gif => "image/gif",
+            LogoExtension::Webp => "image/webp",
+        }
+    }
+
+    /// Whether `data` looks like an image of this format.
+    pub fn matches(&self, data: &[u8]) -> bool {
+        match self {
+            LogoExtension::Png => data.starts_with(b"\x89PNG"),
+            LogoExtension::Jpg | LogoExtension::Jpeg => data.starts_with(b"\xFF\xD8\xFF"),
+            LogoExtension::Gif => data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a"),
+            LogoExtension::Webp => data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP"),
+            LogoExtension::Svg => {
+                let text = data.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(data);
+                let mut text = text.trim_ascii_start();
+                // an xml declaration may precede the root element
+                if text.starts_with(b"<?xml") {
+                    match text.windows(2).position(|window| window == b"?>") {
+                        Some(end) => text = text[end + 2..].trim_ascii_start(),
+                        None => return false,
+                    }
+                }
+                text.starts_with(b"<svg")
+            }
+        }
+    }
 }
 
-#[derive(CandidType, Serialize, Deserialize, Clone, Default)]
+#[derive(CandidType, Serialize, Deserialize, Clone, Default, PartialEq)]
 pub struct Logo {
     extension: LogoExtension,
     data: Vec<u8>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Default)]
+impl Logo {
+    /// An empty logo clears the collection logo and is always accepted.
+    pub fn validate(&self, max_size: usize) -> Result<(), NftError> {
+        if self.data.len() > max_size {
+            Err(NftError::Other(format!(
+                "logo is {} bytes, the collection allows at most {max_size}",
+                self.data.len()
+            )))
+        } else if !self.data.is_empty() && !self.extension.matches(&self.data) {
+            Err(NftError::Other(format!(
+                "logo data is not a valid {} image",
+                self.extension.mime_type()
+            )))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Hex SHA-256 over the mime type and the image bytes.
+    pub fn hash(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.extension.mime_type());
+        hasher.update([0]);
+        hasher.update(&self.data);
+        hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(default)]
 pub struct Collection {
     name: String,
     logo: Logo,
     symbol: String,
-    nfts: Nfts,
     custodians: Custodians,
     operators: Operators,
+    /// The txid of each owner's latest grant or revocation per operator.
+    operator_txids: HashMap<Principal, HashMap<Principal, u128>>,
+    token_id: u64,
+    #[serde(skip)]
+    owner_index: OwnerIndex,
+    royalty_bps: u16,
+    royalty_recipient: Option<Principal>,
+    burned: HashSet<u64>,
+    min_cycles: u64,
+    notifications_disabled: bool,
+    max_logo_size: Option<usize>,
+    max_supply: Option<u64>,
+    roles: Roles,
+    paused: bool,
+    #[serde(skip)]
+    logo_hash: String,
+    denied_recipients: HashSet<Principal>,
+    default_metadata: Vec<MetadataPart>,
+    burn_policy: BurnPolicy,
+    reserved: BTreeSet<u64>,
+    released: u64,
+    claim_allowlist: HashSet<Principal>,
+    transfer_fee: u128,
+    treasury: Option<Principal>,
+    tags: Vec<String>,
+    mint_limit_per_principal: Option<u64>,
+    mint_counts: HashMap<Principal, u64>,
+    unique_content: bool,
+    /// SHA-256 of every live token's content, kept only while
+    /// `unique_content` is set.
+    content_hashes: HashSet<[u8; 32]>,
+    /// Soulbound tokens stay with the principal they were minted to, short of
+    /// an `admin_transfer`. Burning is still up to the burn policy.
+    soulbound: bool,
+    /// Above 1, custodians can only move tokens they are not otherwise
+    /// authorized for through `propose_transfer`.
+    transfer_approvals_required: u8,
+    /// A change that needs several custodians to agree, and the custodians
+    /// who have asked for it so far.
+    pending_custodian_change: Option<(CustodianChange, HashSet<Principal>)>,
+    /// Keys every token must carry, in some metadata part, with a value of
+    /// the given kind.
+    required_metadata_keys: Vec<(String, MetadataValueKind)>,
+    transfer_hooks: Vec<TransferHook>,
+    max_content_bytes: Option<u64>,
+    /// Nanosecond timestamps bounding when non-custodians may mint.
+    mint_start: Option<u64>,
+    mint_end: Option<u64>,
+}
+
+/// Changes that could weaken multi-custodian approval, so they take as many
+/// custodians as `transfer_approvals_required` does.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub enum CustodianChange {
+    LowerApprovals(u8),
+    InsertCustodian(Principal),
+    /// The new custodian set, sorted.
+    ReplaceCustodians(Vec<Principal>),
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+pub enum BurnPolicy {
+    #[default]
+    OwnerOnly,
+    OwnerOrCustodian,
+    /// The owner, the token's approved principal, or an operator of the owner.
+    OwnerOrDelegate,
+    Disabled,
+}
+
+impl Collection {
+    pub fn set_logo(&mut self, logo: Logo) {
+        self.logo_hash = logo.hash();
+        self.logo = logo;
+    }
+
+    /// Custodians implicitly hold every role.
+    pub fn has_role(&self, principal: &Principal, role: Role) -> bool {
+        self.custodians.contains(principal)
+            || self
+                .roles
+                .get(principal)
+                .is_some_and(|roles| roles.contains(&role))
+    }
+
+    /// Fills each part of `metadata` in with the default part of the same
+    /// purpose, keeping the token's value wherever both set a key. Default
+    /// parts whose purpose the token does not use are appended as they are.
+    pub fn merge_metadata(&self, mut metadata: Vec<MetadataPart>) -> Vec<MetadataPart> {
+        for default in &self.default_metadata {
+            match metadata
+                .iter_mut()
+                .find(|part| part.purpose == default.purpose)
+            {
+                Some(part) => {
+                    for (key, value) in &default.key_val_data {
+                        part.key_val_data
+                            .entry(key.to_owned())
+                            .or_insert_with(|| value.clone());
+                    }
+                }
+                None => metadata.push(default.clone()),
+            }
+        }
+        metadata
+    }
+
+    pub fn check_recipient(&self, to: &Principal) -> Result<(), NftError> {
+        if self.denied_recipients.contains(to) {
+            Err(NftError::Other(format!(
+                "{to} may not receive tokens of this collection"
+            )))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn check_transferable(&self) -> Result<(), NftError> {
+        if self.soulbound {
+            Err(NftError::NonTransferable)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn check_paused(&self) -> Result<(), NftError> {
+        if self.paused {
+            Err(NftError::Paused)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Traps on overflow, like the `State` counters.
+    pub fn next_token_id(&mut self) -> u64 {
+        self.token_id = self
+            .token_id
+            .checked_add(1)
+            .expect("token id counter overflowed");
+        self.token_id
+    }
+
+    pub fn max_logo_size(&self) -> usize {
+        self.max_logo_size.unwrap_or(DEFAULT_MAX_LOGO_SIZE)
+    }
+
+    pub fn max_content_bytes(&self) -> u64 {
+        self.max_content_bytes.unwrap_or(DEFAULT_MAX_CONTENT_BYTES)
+    }
+
+    /// Counts the token's content together with the raw data of its metadata
+    /// parts, the two places a caller can put arbitrary bytes.
+    pub fn check_content_size(
+        &self,
+        content: &[u8],
+        metadata: &[MetadataPart],
+    ) -> Result<(), NftError> {
+        let size = content.len() + metadata.iter().map(|part| part.data.len()).sum::<usize>();
+        if size as u64 > self.max_content_bytes() {
+            Err(NftError::Other("content too large".to_owned()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Ids drawn from the mint counter that are minted and not burned, or
+    /// still held back by a reservation. Released reservations never count.
+    pub fn allocated_supply(&self) -> u64 {
+        self.token_id - self.burned.len() as u64 - self.released
+    }
+
+    /// Tokens leave a collection only by burning, so the live supply follows
+    /// from the mint counter without scanning the NFTs.
+    pub fn live_supply(&self) -> u64 {
+        self.allocated_supply() - self.reserved.len() as u64
+    }
+
+    /// Reserved ids already count as allocated, so claiming one needs no
+    /// further supply check.
+    pub fn check_supply(&self, count: usize) -> Result<(), NftError> {
+        match self.max_supply {
+            Some(max) if self.allocated_supply().saturating_add(count as u64) > max => {
+                Err(NftError::SupplyExhausted)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks merged metadata, so keys the defaults fill in count as present.
+    pub fn check_schema(&self, metadata: &[MetadataPart]) -> Result<(), NftError> {
+        for (key, kind) in &self.required_metadata_keys {
+            match metadata.iter().find_map(|part| part.key_val_data.get(key)) {
+                None => {
+                    return Err(NftError::Other(format!(
+                        "metadata is missing required key {key}"
+                    )))
+                }
+                Some(value) if value.kind() != *kind => {
+                    return Err(NftError::Other(format!(
+                        "metadata key {key} has the wrong type"
+                    )))
+                }
+                Some(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Custodians mint outside the window, e.g. for team reserves.
+    pub fn check_mint_window(&self, caller: &Principal) -> Result<(), NftError> {
+        let now = runtime::time();
+        if self.custodians.contains(caller) {
+            Ok(())
+        } else if self.mint_start.is_some_and(|start| now < start) {
+            Err(NftError::Other("mint not started".to_owned()))
+        } else if self.mint_end.is_some_and(|end| now >= end) {
+            Err(NftError::Other("mint ended".to_owned()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Counts tokens minted to a principal, whoever the minter was.
+    pub fn check_mint_limit(&self, to: &Principal, count: u64) -> Result<(), NftError> {
+        match self.mint_limit_per_principal {
+            Some(limit) if self.mints_by(to).saturating_add(count) > limit => {
+                Err(NftError::Other("mint limit reached".to_owned()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn mints_by(&self, principal: &Principal) -> u64 {
+        self.mint_counts.get(principal).copied().unwrap_or_default()
+    }
+
+    pub fn count_mint(&mut self, to: Principal) {
+        *self.mint_counts.entry(to).or_default() += 1;
+    }
+
+    /// Returns the hash to `track_content` once the token is stored, or
+    /// `None` when the collection allows duplicate content.
+    pub fn check_content(&self, content: &[u8]) -> Result<Option<[u8; 32]>, NftError> {
+        if !self.unique_content {
+            return Ok(None);
+        }
+        let hash: [u8; 32] = Sha256::digest(content).into();
+        if self.content_hashes.contains(&hash) {
+            Err(NftError::Other("duplicate content".to_owned()))
+        } else {
+            Ok(Some(hash))
+        }
+    }
+
+    pub fn track_content(&mut self, hash: Option<[u8; 32]>) {
+        if let Some(hash) = hash {
+            self.content_hashes.insert(hash);
+        }
+    }
+
+    pub fn check_cycles(&self) -> Result<(), NftError> {
+        if runtime::canister_balance() < self.min_cycles {
+            Err(NftError::Other(format!(
+                "cycles balance is below the collection threshold of {}",
+                self.min_cycles
+            )))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn balance_of(&self, principal: &Principal) -> usize {
+        self.owner_index
+            .get(principal)
+            .map(|tokens| tokens.len())
+            .unwrap_or_default()
+    }
+
+    pub fn reindex(&mut self, token_id: u64, from: Option<Principal>, to: Option<Principal>) {
+        if let Some(from) = from {
+            if let Some(tokens) = self.owner_index.get_mut(&from) {
+                tokens.remove(&token_id);
+                if tokens.is_empty() {
+                    self.owner_index.remove(&from);
+                }
+            }
+        }
+        if let Some(to) = to {
+            self.owner_index.entry(to).or_default().insert(token_id);
+        }
+    }
+
+    /// Adds the caller's vote for `change` and returns whether enough
+    /// custodians now agree to it. Only one change is pending at a time: a
+    /// vote for a different one starts the count over.
+    pub fn vote_custodian_change(&mut self, caller: Principal, change: CustodianChange) -> bool {
+        let required = self.transfer_approvals_required.max(1) as usize;
+        let mut votes = match self.pending_custodian_change.take() {
+            Some((pending, votes)) if pending == change => votes,
+            _ => HashSet::new(),
+        };
+        votes.insert(caller);
+        votes.retain(|custodian| self.custodians.contains(custodian));
+        if votes.len() < required {
+            self.pending_custodian_change = Some((change, votes));
+            false
+        } else {
+            true
+        }
+    }
+
+    /// Fails when a lone custodian may not move tokens it holds no approval
+    /// for, because the collection asks for several custodians to agree.
+    pub fn check_lone_custodian(&self) -> Result<(), NftError> {
+        if self.transfer_approvals_required > 1 {
+            Err(NftError::Other(format!(
+                "custodian transfers need {} approvals, use propose_transfer",
+                self.transfer_approvals_required
+            )))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn check_transfer(
+        &self,
+        nft: &Nft,
+        caller: Principal,
+        from: Principal,
+    ) -> Result<(), NftError> {
+        self.check_paused()?;
+        self.check_transferable()?;
+        let delegated = nft.owner == caller
+            || nft.approved_principal() == Some(caller)
+            || self
+                .operators
+                .get(&from)
+                .map(|operators| operators.contains(&caller))
+                .unwrap_or(false);
+        if !delegated {
+            if !self.custodians.contains(&caller) {
+                return Err(NftError::Unauthorized);
+            }
+            self.check_lone_custodian()?;
+        }
+        if nft.owner != from {
+            // only reached by authorized callers, so the owner is not leaked
+            Err(NftError::WrongFrom {
+                actual_owner: nft.owner,
+            })
+        } else if nft.is_locked() {
+            Err(NftError::Locked)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Role {
+    Minter,
+    Pauser,
+    MetadataEditor,
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone)]
 pub struct Nft {
     id: u64,
     owner: Principal,
-    approved: Option<Principal>,
+    #[serde(deserialize_with = "deserialize_approval")]
+    approved: Option<(Principal, Option<u64>)>,
+    /// The txid of the approval in `approved`.
+    approval_txid: Option<u128>,
     metadata: Vec<MetadataPart>,
     content: Vec<u8>,
+    pgn: Option<String>,
+    fen: Option<String>,
+    locked_until: Option<u64>,
+    #[serde(default)]
+    owner_history: Vec<(Principal, u64)>,
+    minted_hash: Option<[u8; 32]>,
+    #[serde(default)]
+    minted_at: u64,
+    set_id: Option<u64>,
+    random_seed: Option<Vec<u8>>,
+    /// The recipient of a pull transfer, who takes the token with
+    /// `accept_transfer`.
+    offered_to: Option<Principal>,
 }
 
-#[derive(CandidType, Serialize, Deserialize, Clone)]
+impl Nft {
+    pub fn new(id: u64, owner: Principal, metadata: Vec<MetadataPart>, content: Vec<u8>) -> Self {
+        let now = runtime::time();
+        let mut nft = Nft {
+            id,
+            owner,
+            approved: None,
+            approval_txid: None,
+            metadata,
+            content,
+            pgn: None,
+            fen: None,
+            locked_until: None,
+            owner_history: vec![(owner, now)],
+            minted_hash: None,
+            minted_at: now,
+            set_id: None,
+            random_seed: None,
+            offered_to: None,
+        };
+        nft.minted_hash = Some(nft.hash());
+        nft
+    }
+
+    /// SHA-256 over the content and the metadata. Every field is length
+    /// prefixed and key-value pairs are hashed in key order, so the digest
+    /// does not depend on `HashMap` iteration order.
+    pub fn hash(&self) -> [u8; 32] {
+        fn bytes(hasher: &mut Sha256, bytes: &[u8]) {
+            hasher.update((bytes.len() as u64).to_le_bytes());
+            hasher.update(bytes);
+        }
+        let mut hasher = Sha256::new();
+        bytes(&mut hasher, &self.content);
+        hasher.update((self.metadata.len() as u64).to_le_bytes());
+        for part in &self.metadata {
+            hasher.update([match part.purpose {
+                MetadataPurpose::Preview => 0,
+                MetadataPurpose::Rendered => 1,
+            }]);
+            let mut entries: Vec<_> = part.key_val_data.iter().collect();
+            entries.sort_by_key(|(key, _)| *key);
+            hasher.update((entries.len() as u64).to_le_bytes());
+            for (key, value) in entries {
+                bytes(&mut hasher, key.as_bytes());
+                let (tag, value) = match value {
+                    MetadataValue::Text(text) => (0, text.as_bytes().to_vec()),
+                    MetadataValue::Blob(blob) => (1, blob.clone()),
+                    MetadataValue::Nat8(n) => (2, n.to_le_bytes().to_vec()),
+                    MetadataValue::Nat16(n) => (3, n.to_le_bytes().to_vec()),
+                    MetadataValue::Nat32(n) => (4, n.to_le_bytes().to_vec()),
+                    MetadataValue::Nat64(n) => (5, n.to_le_bytes().to_vec()),
+                    MetadataValue::Nat(n) => (6, n.to_le_bytes().to_vec()),
+                };
+                hasher.update([tag]);
+                bytes(&mut hasher, &value);
+            }
+            bytes(&mut hasher, &part.data);
+        }
+        hasher.finalize().into()
+    }
+
+    /// The approved principal, unless its approval has expired.
+    pub fn approved_principal(&self) -> Option<Principal> {
+        self.approved
+            .filter(|(_, expires_at)| {
+                expires_at.is_none_or(|expires_at| runtime::time() < expires_at)
+            })
+            .map(|(principal, _)| principal)
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked_until
+            .is_some_and(|until| runtime::time() < until)
+    }
+}
+
+/// Tokens stored before approvals could expire hold a bare principal, which
+/// reads back as an approval without expiry.
+fn deserialize_approval<'de, D>(
+    deserializer: D,
+) -> Result<Option<(Principal, Option<u64>)>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StoredApproval {
+        Expiring(Principal, Option<u64>),
+        Plain(Principal),
+    }
+    Ok(
+        Option::<StoredApproval>::deserialize(deserializer)?.map(|approval| match approval {
+            StoredApproval::Expiring(principal, expires_at) => (principal, expires_at),
+            StoredApproval::Plain(principal) => (principal, None),
+        }),
+    )
+}
+
+impl Storable for Transaction {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize transaction"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(&bytes).expect("failed to deserialize transaction")
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_TRANSACTION_SIZE,
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for Nft {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("failed to serialize nft"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(&bytes).expect("failed to deserialize nft")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, PartialEq)]
 pub struct MetadataPart {
     purpose: MetadataPurpose,
     key_val_data: HashMap<String, MetadataValue>,
@@ -76,8 +1137,28 @@ pub enum MetadataPurpose {
     Rendered,
 }
 
+/// A token carries at most one part per purpose, so renderers find a single
+/// preview and a single rendered image. Duplicates are rejected, not merged.
+fn check_purposes(metadata: &[MetadataPart]) -> Result<(), NftError> {
+    for (i, part) in metadata.iter().enumerate() {
+        if metadata[..i]
+            .iter()
+            .any(|other| other.purpose == part.purpose)
+        {
+            return Err(NftError::Other(format!(
+                "metadata has more than one {} part",
+                match part.purpose {
+                    MetadataPurpose::Preview => "Preview",
+                    MetadataPurpose::Rendered => "Rendered",
+                }
+            )));
+        }
+    }
+    Ok(())
+}
+
 #[derive(CandidType, Serialize, Deserialize, Clone)]
-enum MetadataValue {
+pub enum MetadataValue {
     Text(String),
     Blob(Vec<u8>),
     Nat8(u8),
@@ -87,29 +1168,315 @@ enum MetadataValue {
     Nat(u128),
 }
 
-#[derive(CandidType, Deserialize)]
-enum Interface {
-    Approval,
-    Burn,
-    TransferNotification,
+/// The variant a metadata schema expects a value to be, naturals included:
+/// a `Nat16` does not satisfy a key declared `Nat`.
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum MetadataValueKind {
+    Text,
+    Blob,
+    Nat8,
+    Nat16,
+    Nat32,
+    Nat64,
+    Nat,
 }
 
-#[pre_upgrade]
-fn pre_upgrade() {
-    let serialized_state = serde_cbor::to_vec(&STATE.with(|state| state.borrow().clone()))
-        .expect("failed to serialize collections");
-    storage::stable_save((serialized_state,)).expect("failed to stable save")
-}
-#[post_upgrade]
-fn post_upgrade() {
-    let (state,): (Vec<u8>,) = storage::stable_restore().expect("failed to restore");
-    let deserialized_state: State =
-        serde_cbor::from_slice(&state).expect("failed to deserialize collections");
-    STATE.with(|state| {
-        let mut borrowed = state.borrow_mut();
-        borrowed.collections.extend(deserialized_state.collections);
-        borrowed.txid = deserialized_state.txid
-    })
+impl MetadataValue {
+    pub fn kind(&self) -> MetadataValueKind {
+        match self {
+            MetadataValue::Text(_) => MetadataValueKind::Text,
+            MetadataValue::Blob(_) => MetadataValueKind::Blob,
+            MetadataValue::Nat8(_) => MetadataValueKind::Nat8,
+            MetadataValue::Nat16(_) => MetadataValueKind::Nat16,
+            MetadataValue::Nat32(_) => MetadataValueKind::Nat32,
+            MetadataValue::Nat64(_) => MetadataValueKind::Nat64,
+            MetadataValue::Nat(_) => MetadataValueKind::Nat,
+        }
+    }
+
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            MetadataValue::Text(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    pub fn as_blob(&self) -> Option<&[u8]> {
+        match self {
+            MetadataValue::Blob(blob) => Some(blob),
+            _ => None,
+        }
+    }
+
+    /// Any natural, widened to `u128`.
+    pub fn as_nat(&self) -> Option<u128> {
+        match *self {
+            MetadataValue::Nat8(n) => Some(n.into()),
+            MetadataValue::Nat16(n) => Some(n.into()),
+            MetadataValue::Nat32(n) => Some(n.into()),
+            MetadataValue::Nat64(n) => Some(n.into()),
+            MetadataValue::Nat(n) => Some(n),
+            MetadataValue::Text(_) | MetadataValue::Blob(_) => None,
+        }
+    }
+}
+
+/// Naturals compare by value whatever their width, so a rating stored as
+/// `Nat16` matches a query for the same `Nat`.
+impl PartialEq for MetadataValue {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (MetadataValue::Text(a), MetadataValue::Text(b)) => a == b,
+            (MetadataValue::Blob(a), MetadataValue::Blob(b)) => a == b,
+            _ => self.as_nat().is_some() && self.as_nat() == other.as_nat(),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Debug, PartialEq)]
+pub enum NftError {
+    Unauthorized,
+    InvalidCollection,
+    InvalidToken,
+    ZeroAddress,
+    SelfApprove,
+    Locked,
+    SupplyExhausted,
+    Paused,
+    NonTransferable,
+    WrongFrom { actual_owner: Principal },
+    Other(String),
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct HttpRequest {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+#[derive(CandidType)]
+pub struct HttpResponse {
+    status_code: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl HttpResponse {
+    fn ok(content_type: &str, body: Vec<u8>) -> Self {
+        HttpResponse {
+            status_code: 200,
+            headers: vec![("Content-Type".to_owned(), content_type.to_owned())],
+            body,
+        }
+    }
+
+    fn not_modified(etag: &str) -> Self {
+        HttpResponse {
+            status_code: 304,
+            headers: vec![("ETag".to_owned(), etag.to_owned())],
+            body: vec![],
+        }
+    }
+
+    fn not_found() -> Self {
+        HttpResponse {
+            status_code: 404,
+            headers: vec![("Content-Type".to_owned(), "text/plain".to_owned())],
+            body: b"not found".to_vec(),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+enum Interface {
+    Approval,
+    Burn,
+    Mint,
+    TransferNotification,
+}
+
+#[init]
+fn init() {
+    STATE.with(|state| state.borrow_mut().upgraded_at = runtime::time());
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    let serialized_state = serde_cbor::to_vec(&STATE.with(|state| state.borrow().clone()))
+        .expect("failed to serialize collections");
+    let mut memory = MEMORY_MANAGER.with(|manager| manager.borrow().get(UPGRADES_MEMORY));
+    let mut writer = Writer::new(&mut memory, 0);
+    writer
+        .write(&(serialized_state.len() as u64).to_le_bytes())
+        .expect("failed to stable save");
+    writer
+        .write(&serialized_state)
+        .expect("failed to stable save")
+}
+#[post_upgrade]
+fn post_upgrade() {
+    // must be checked before the memory manager is first touched, which
+    // would lay its own header over the legacy state
+    let deserialized_state = if has_legacy_layout() {
+        let (state,): (Vec<u8>,) = ic_cdk::storage::stable_restore().expect("failed to restore");
+        migrate_legacy(&state)
+    } else {
+        let memory = MEMORY_MANAGER.with(|manager| manager.borrow().get(UPGRADES_MEMORY));
+        let mut len = [0; 8];
+        memory.read(0, &mut len);
+        let mut state = vec![0; u64::from_le_bytes(len) as usize];
+        memory.read(len.len() as u64, &mut state);
+        serde_cbor::from_slice(&state).expect("failed to deserialize collections")
+    };
+    let mut restored = restore(deserialized_state);
+    restored.upgraded_at = runtime::time();
+    STATE.with(|state| *state.borrow_mut() = restored);
+}
+
+/// Versions that kept NFTs inside their collection saved the whole state
+/// with `stable_save`, which starts stable memory with a Candid header
+/// rather than the memory manager's magic.
+fn has_legacy_layout() -> bool {
+    let mut magic = [0; 3];
+    if api::stable::stable64_size() == 0 {
+        return false;
+    }
+    api::stable::stable64_read(0, &mut magic);
+    &magic != b"MGR"
+}
+
+/// The part of a legacy collection that `Collection` no longer has.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct LegacyCollection {
+    nfts: HashMap<u64, Nft>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct LegacyState {
+    collections: HashMap<usize, LegacyCollection>,
+}
+
+/// Decodes state saved in the legacy layout, moving each collection's NFTs
+/// into `NFTS`. The owner index is left to `restore`.
+fn migrate_legacy(bytes: &[u8]) -> State {
+    let mut state: State =
+        serde_cbor::from_slice(bytes).expect("failed to deserialize collections");
+    let legacy: LegacyState =
+        serde_cbor::from_slice(bytes).expect("failed to deserialize legacy nfts");
+    NFTS.with(|nfts| {
+        let mut nfts = nfts.borrow_mut();
+        for (collection_id, legacy_collection) in legacy.collections {
+            for (token_id, nft) in legacy_collection.nfts {
+                // legacy collections kept no mint counter
+                if let Some(collection) = state.collections.get_mut(&collection_id) {
+                    collection.token_id = collection.token_id.max(token_id);
+                }
+                nfts.insert(nft_key(collection_id, token_id), nft);
+            }
+        }
+    });
+    state
+}
+
+/// Brings state saved by any earlier version up to date and rebuilds what is
+/// not saved. Traps rather than letting the canister run on state that does
+/// not line up with the stored NFTs.
+fn restore(mut state: State) -> State {
+    // state saved before the counter existed derived ids from the collection count
+    state.collection_id = state
+        .collections
+        .keys()
+        .copied()
+        .max()
+        .unwrap_or_default()
+        .max(state.collection_id);
+    // state saved before the counts were kept still has its full ledger
+    if state.op_counts.is_empty() {
+        for tx in &state.transactions {
+            *state.op_counts.entry(tx.op.name().to_owned()).or_default() += 1;
+        }
+    }
+    TRANSACTIONS.with(|transactions| {
+        let transactions = transactions.borrow();
+        for tx in state.transactions.drain(..) {
+            transactions.push(&tx).expect("failed to grow transactions");
+        }
+    });
+    for collection in state.collections.values_mut() {
+        collection.logo_hash = collection.logo.hash();
+        // revoking used to leave empty operator sets behind
+        collection
+            .operators
+            .retain(|_, operators| !operators.is_empty());
+    }
+    NFTS.with(|nfts| {
+        let mut approved = Vec::new();
+        for ((collection_id, token_id), nft) in nfts.borrow().iter() {
+            if let Some(collection) = state.collections.get_mut(&(collection_id as usize)) {
+                collection.reindex(token_id, None, Some(nft.owner))
+            }
+            if nft.approved.is_some() {
+                approved.push(((collection_id, token_id), nft));
+            }
+        }
+        // rewrite approvals saved as a bare principal in the current format
+        let mut nfts = nfts.borrow_mut();
+        for (key, nft) in approved {
+            nfts.insert(key, nft);
+        }
+    });
+    if let Err(err) = check_invariants(&state) {
+        panic!("restored state is inconsistent: {err}");
+    }
+    state
+}
+
+fn check_invariants(state: &State) -> Result<(), String> {
+    TRANSACTIONS.with(|transactions| {
+        let transactions = transactions.borrow();
+        let last = transactions.len().checked_sub(1);
+        match (
+            transactions.get(0),
+            last.and_then(|last| transactions.get(last)),
+        ) {
+            (Some(first), Some(last))
+                if last.id != state.txid
+                    || last.id - first.id + 1 != transactions.len() as u128 =>
+            {
+                Err(format!(
+                    "transactions {} to {} are not contiguous up to txid {}",
+                    first.id, last.id, state.txid
+                ))
+            }
+            _ => Ok(()),
+        }
+    })?;
+    NFTS.with(|nfts| {
+        for ((collection_id, token_id), nft) in nfts.borrow().iter() {
+            if nft.id != token_id {
+                return Err(format!(
+                    "token {token_id} of collection {collection_id} is stored with id {}",
+                    nft.id
+                ));
+            }
+        }
+        Ok(())
+    })?;
+    for (collection_id, collection) in &state.collections {
+        let mut indexed = HashSet::new();
+        for tokens in collection.owner_index.values() {
+            if let Some(token_id) = tokens.iter().find(|&&token_id| !indexed.insert(token_id)) {
+                return Err(format!(
+                    "token {token_id} of collection {collection_id} is indexed under two owners"
+                ));
+            }
+        }
+    }
+    Ok(())
 }
 
 #[derive(CandidType, Deserialize)]
@@ -119,342 +1486,5146 @@ pub struct InsertCollection {
     symbol: String,
 }
 
+/// The caller becomes the first custodian of the new collection, paying
+/// the creation fee, if any, in attached cycles.
 #[update]
-pub fn insert_collection(collection: InsertCollection) -> usize {
-    let id = STATE.with(|state| state.borrow().collections.len().wrapping_add(1));
+pub fn insert_collection(collection: InsertCollection) -> Result<usize, NftError> {
+    let caller = runtime::caller();
+    if caller == ANONYMOUS {
+        return Err(NftError::ZeroAddress);
+    }
+    validate_label("name", &collection.name, MAX_NAME_LEN)?;
+    validate_label("symbol", &collection.symbol, MAX_SYMBOL_LEN)?;
     STATE.with(|state| {
-        state.borrow_mut().collections.insert(
-            id,
-            Collection {
-                name: collection.name,
-                logo: collection.logo,
-                symbol: collection.symbol,
-                ..Default::default()
-            },
-        )
-    });
-    id
+        let mut state = state.borrow_mut();
+        let fee = state.creation_fee;
+        if runtime::msg_cycles_available() < fee {
+            return Err(NftError::Other(format!(
+                "creating a collection costs {fee} cycles"
+            )));
+        }
+        runtime::msg_cycles_accept(fee);
+        let id = state.next_collection_id();
+        let mut inserted = Collection {
+            name: collection.name,
+            symbol: collection.symbol,
+            custodians: HashSet::from([caller]),
+            ..Default::default()
+        };
+        inserted.set_logo(collection.logo);
+        state.collections.insert(id, inserted);
+        Ok(id)
+    })
+}
+
+fn validate_label(field: &str, value: &str, max_len: usize) -> Result<(), NftError> {
+    if value.trim().is_empty() {
+        Err(NftError::Other(format!("{field} must not be empty")))
+    } else if value.chars().count() > max_len {
+        Err(NftError::Other(format!(
+            "{field} must be at most {max_len} characters"
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+/// Protocol-level admin, as opposed to the collection-level admin of
+/// custodians: the canister's controllers, e.g. a governance canister.
+fn is_controller(principal: Principal) -> bool {
+    runtime::is_controller(&principal)
 }
 
+/// Custodians may delete an empty collection. Deleting one that still has
+/// tokens destroys them, which only controllers may do.
 #[update]
-fn set_name_of_collection(collection_id: usize, name: String) {
+fn delete_collection(collection_id: usize) -> Result<(), NftError> {
+    let caller = runtime::caller();
     STATE.with(|state| {
         let mut state = state.borrow_mut();
         let collection = state
             .collections
-            .get_mut(&collection_id)
-            .expect("invalid collection id");
-        if collection.custodians.contains(&api::caller()) {
-            collection.name = name
+            .get(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        let tokens: Vec<u64> = NFTS.with(|nfts| {
+            nfts_of_collection(&nfts.borrow(), collection_id)
+                .map(|nft| nft.id)
+                .collect()
+        });
+        if !collection.custodians.contains(&caller) && !is_controller(caller) {
+            Err(NftError::Unauthorized)
+        } else if !tokens.is_empty() && !is_controller(caller) {
+            Err(NftError::Other("collection still has nfts".to_owned()))
         } else {
-            panic!("unauthorized")
+            NFTS.with(|nfts| {
+                let mut nfts = nfts.borrow_mut();
+                for token_id in tokens {
+                    nfts.remove(&nft_key(collection_id, token_id));
+                }
+            });
+            state.collections.remove(&collection_id);
+            state
+                .uploads
+                .retain(|_, upload| upload.collection_id != collection_id);
+            state.notes.retain(|&(id, _), _| id != collection_id as u64);
+            Ok(())
         }
     })
 }
 
+/// A collection and its tokens, for backups and migrations. Settings beyond
+/// those listed here (roles, policies, limits) start from their defaults on
+/// import, so the logo must fit the default size limit.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct CollectionExport {
+    id: usize,
+    name: String,
+    symbol: String,
+    logo: Logo,
+    custodians: Vec<Principal>,
+    operators: Vec<(Principal, Vec<Principal>)>,
+    token_id: u64,
+    burned: Vec<u64>,
+    reserved: Vec<u64>,
+    released: u64,
+    royalty_bps: u16,
+    royalty_recipient: Option<Principal>,
+    max_supply: Option<u64>,
+    tokens: Vec<Nft>,
+}
+
+#[query]
+fn export_collection(collection_id: usize, include_content: bool) -> Option<CollectionExport> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let collection = state.collections.get(&collection_id)?;
+        let tokens = NFTS.with(|nfts| {
+            nfts_of_collection(&nfts.borrow(), collection_id)
+                .map(|mut nft| {
+                    if !include_content {
+                        nft.content.clear();
+                    }
+                    nft
+                })
+                .collect()
+        });
+        Some(CollectionExport {
+            id: collection_id,
+            name: collection.name.to_owned(),
+            symbol: collection.symbol.to_owned(),
+            logo: collection.logo.to_owned(),
+            custodians: collection.custodians.iter().copied().collect(),
+            operators: collection
+                .operators
+                .iter()
+                .map(|(owner, operators)| (*owner, operators.iter().copied().collect()))
+                .collect(),
+            token_id: collection.token_id,
+            burned: collection.burned.iter().copied().collect(),
+            reserved: collection.reserved.iter().copied().collect(),
+            released: collection.released,
+            royalty_bps: collection.royalty_bps,
+            royalty_recipient: collection.royalty_recipient,
+            max_supply: collection.max_supply,
+            tokens,
+        })
+    })
+}
+
+/// Restores an exported collection under its original id. Only canister
+/// controllers may import, and the id must not be in use.
 #[update]
-fn set_symbol_of_collection(collection_id: usize, symbol: String) {
+fn import_collection(export: CollectionExport) -> Result<usize, NftError> {
+    if !is_controller(runtime::caller()) {
+        return Err(NftError::Unauthorized);
+    }
+    validate_label("name", &export.name, MAX_NAME_LEN)?;
+    validate_label("symbol", &export.symbol, MAX_SYMBOL_LEN)?;
+    if export.custodians.is_empty() {
+        return Err(NftError::Other("collection has no custodians".to_owned()));
+    }
+    export.logo.validate(DEFAULT_MAX_LOGO_SIZE)?;
+    let burned: HashSet<u64> = export.burned.into_iter().collect();
+    let reserved: BTreeSet<u64> = export.reserved.into_iter().collect();
+    // every id drawn from the counter is live, burned, reserved or released
+    let mut seen = HashSet::new();
+    let ids = export
+        .tokens
+        .iter()
+        .map(|nft| nft.id)
+        .chain(burned.iter().copied())
+        .chain(reserved.iter().copied());
+    for token_id in ids {
+        if token_id == 0 || token_id > export.token_id {
+            return Err(NftError::Other(format!(
+                "token id {token_id} is out of range"
+            )));
+        } else if !seen.insert(token_id) {
+            return Err(NftError::Other(format!("duplicate token id {token_id}")));
+        }
+    }
+    if seen.len() as u64 + export.released > export.token_id {
+        return Err(NftError::Other(format!(
+            "{} released ids do not fit in the mint counter",
+            export.released
+        )));
+    }
+    if export.tokens.iter().any(|nft| nft.owner == ANONYMOUS) {
+        return Err(NftError::ZeroAddress);
+    }
+    let id = export.id;
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        let collection = state
-            .collections
-            .get_mut(&collection_id)
-            .expect("invalid collection id");
-        if collection.custodians.contains(&api::caller()) {
-            collection.symbol = symbol
-        } else {
-            panic!("unauthorized")
+        if state.collections.contains_key(&id)
+            || NFTS.with(|nfts| nfts_of_collection(&nfts.borrow(), id).next().is_some())
+        {
+            return Err(NftError::Other(format!("collection {id} already exists")));
         }
+        let mut collection = Collection {
+            name: export.name,
+            symbol: export.symbol,
+            custodians: export.custodians.into_iter().collect(),
+            operators: export
+                .operators
+                .into_iter()
+                .map(|(owner, operators)| (owner, operators.into_iter().collect()))
+                .collect(),
+            token_id: export.token_id,
+            burned,
+            reserved,
+            released: export.released,
+            royalty_bps: export.royalty_bps.min(MAX_ROYALTY_BPS),
+            royalty_recipient: export.royalty_recipient,
+            max_supply: export.max_supply,
+            ..Default::default()
+        };
+        collection.set_logo(export.logo);
+        NFTS.with(|nfts| {
+            let mut nfts = nfts.borrow_mut();
+            for nft in export.tokens {
+                collection.reindex(nft.id, None, Some(nft.owner));
+                nfts.insert(nft_key(id, nft.id), nft);
+            }
+        });
+        state.collections.insert(id, collection);
+        // keep fresh ids clear of the imported one
+        state.collection_id = state.collection_id.max(id);
+        Ok(id)
     })
 }
 
 #[update]
-fn set_logo_of_collection(collection_id: usize, logo: Logo) {
+fn set_creation_fee(cycles: u64) -> Result<(), NftError> {
+    if !is_controller(runtime::caller()) {
+        return Err(NftError::Unauthorized);
+    }
+    STATE.with(|state| state.borrow_mut().creation_fee = cycles);
+    Ok(())
+}
+
+#[query]
+fn creation_fee() -> u64 {
+    STATE.with(|state| state.borrow().creation_fee)
+}
+
+#[update]
+fn set_cycles_threshold(collection_id: usize, min_cycles: u64) -> Result<(), NftError> {
     STATE.with(|state| {
         let mut state = state.borrow_mut();
         let collection = state
             .collections
             .get_mut(&collection_id)
-            .expect("invalid collection id");
-        if collection.custodians.contains(&api::caller()) {
-            collection.logo = logo
+            .ok_or(NftError::InvalidCollection)?;
+        if collection.custodians.contains(&runtime::caller()) {
+            collection.min_cycles = min_cycles;
+            Ok(())
         } else {
-            panic!("unauthorized")
+            Err(NftError::Unauthorized)
         }
     })
 }
 
 #[query]
-fn name_of_collection(collection_id: usize) -> Option<String> {
-    STATE.with(|state| {
-        state
-            .borrow()
-            .collections
-            .get(&collection_id)
-            .map(|collection| collection.name.to_owned())
-    })
+fn cycles_balance() -> u64 {
+    runtime::canister_balance()
 }
 
-#[query]
-fn symbol_of_collection(collection_id: usize) -> Option<String> {
-    STATE.with(|state| {
-        state
-            .borrow()
-            .collections
-            .get(&collection_id)
-            .map(|collection| collection.symbol.to_owned())
-    })
+#[update]
+fn deposit_cycles() -> u64 {
+    runtime::msg_cycles_accept(runtime::msg_cycles_available())
 }
 
-#[query]
-fn logo_of_collection(collection_id: usize) -> Option<Logo> {
-    STATE.with(|state| {
-        state
-            .borrow()
-            .collections
-            .get(&collection_id)
-            .map(|collection| collection.logo.to_owned())
-    })
+#[derive(CandidType)]
+pub struct CollectionSummary {
+    id: usize,
+    name: String,
+    symbol: String,
+    total_supply: usize,
+    max_supply: Option<u64>,
+    tags: Vec<String>,
+    mint_start: Option<u64>,
+    mint_end: Option<u64>,
 }
 
 #[query]
-fn balance_of_user(collection_id: usize, principal: Principal) -> usize {
+fn list_collections() -> Vec<CollectionSummary> {
     STATE.with(|state| {
-        state
-            .borrow()
-            .collections
-            .get(&collection_id)
-            .map(|collection| {
-                collection
-                    .nfts
-                    .values()
-                    .filter(|nft| nft.owner == principal)
-                    .count()
-            })
-            .unwrap_or_default()
+        let state = state.borrow();
+        let mut summaries: Vec<CollectionSummary> = NFTS.with(|nfts| {
+            let nfts = nfts.borrow();
+            state
+                .collections
+                .iter()
+                .map(|(id, collection)| CollectionSummary {
+                    id: *id,
+                    name: collection.name.to_owned(),
+                    symbol: collection.symbol.to_owned(),
+                    total_supply: nfts_of_collection(&nfts, *id).count(),
+                    max_supply: collection.max_supply,
+                    tags: collection.tags.to_owned(),
+                    mint_start: collection.mint_start,
+                    mint_end: collection.mint_end,
+                })
+                .collect()
+        });
+        summaries.sort_unstable_by_key(|summary| summary.id);
+        summaries
     })
 }
 
-#[query]
-fn owner_of_nft(collection_id: usize, token_id: u64) -> Option<Principal> {
-    STATE.with(|state| {
-        state
-            .borrow()
-            .collections
-            .get(&collection_id)
-            .and_then(|collection| {
-                collection
-                    .nfts
-                    .values()
-                    .find(|nft| nft.id == token_id)
-                    .map(|nft| nft.owner)
-            })
-    })
+#[update]
+fn mint(
+    collection_id: usize,
+    to: Principal,
+    metadata: Vec<MetadataPart>,
+    mut content: Vec<u8>,
+    idempotency_key: Option<[u8; 32]>,
+) -> Result<u64, NftError> {
+    mint_staged(collection_id, to, metadata, &mut content, idempotency_key)
 }
 
-#[update]
-fn transfer_from_to(collection_id: usize, token_id: u64, from: Principal, to: Principal) -> u128 {
-    if to == ANONYMOUS {
-        panic!("zero address")
+/// Does the work of `mint`, but only takes `content` once the token is
+/// minted, so the bytes stay with the caller when any check fails.
+fn mint_staged(
+    collection_id: usize,
+    to: Principal,
+    metadata: Vec<MetadataPart>,
+    content: &mut Vec<u8>,
+    idempotency_key: Option<[u8; 32]>,
+) -> Result<u64, NftError> {
+    if let Err(err) = reject_reserved_principal(to) {
+        Err(err)
     } else {
-        let caller = api::caller();
+        check_purposes(&metadata)?;
+        let caller = runtime::caller();
         STATE.with(|state| {
             let mut state = state.borrow_mut();
+            if let Some(token_id) = state.replay(caller, idempotency_key, TxOp::Mint)? {
+                return Ok(token_id as u64);
+            }
             let collection = state
                 .collections
                 .get_mut(&collection_id)
-                .expect("invalid collection id");
-            let nft = collection
-                .nfts
-                .get_mut(&token_id)
-                .expect("invalid token id");
-            if nft.owner != caller
-                && nft.approved != Some(caller)
-                && !collection
-                    .operators
-                    .get(&from)
-                    .map(|operators| operators.contains(&caller))
-                    .unwrap_or(false)
-                && !collection.custodians.contains(&caller)
-            {
-                panic!("unauthorized")
-            } else if nft.owner != from {
-                panic!("other")
+                .ok_or(NftError::InvalidCollection)?;
+            if collection.has_role(&caller, Role::Minter) {
+                collection.check_cycles()?;
+                collection.check_mint_window(&caller)?;
+                collection.check_supply(1)?;
+                collection.check_mint_limit(&to, 1)?;
+                let metadata = collection.merge_metadata(metadata);
+                collection.check_schema(&metadata)?;
+                collection.check_content_size(content, &metadata)?;
+                let hash = collection.check_content(content)?;
+                let token_id = collection.next_token_id();
+                NFTS.with(|nfts| {
+                    nfts.borrow_mut().insert(
+                        nft_key(collection_id, token_id),
+                        Nft::new(token_id, to, metadata, std::mem::take(content)),
+                    )
+                });
+                collection.reindex(token_id, None, Some(to));
+                collection.count_mint(to);
+                collection.track_content(hash);
+                state.record(TxOp::Mint, collection_id, Some(token_id), None, Some(to));
+                state.remember(caller, idempotency_key, TxOp::Mint, token_id.into());
+                Ok(token_id)
             } else {
-                nft.approved = None;
-                nft.owner = to;
-                state.next_txid()
+                Err(NftError::Unauthorized)
             }
         })
     }
 }
 
-#[query]
-fn supported_interfaces() -> Vec<Interface> {
-    vec![
-        Interface::Approval,
-        Interface::TransferNotification,
-        Interface::Burn,
-    ]
-}
-
-#[query]
-fn total_supply() -> usize {
+#[update]
+fn batch_mint(
+    collection_id: usize,
+    items: Vec<(Principal, Vec<MetadataPart>, Vec<u8>)>,
+) -> Result<Vec<u64>, NftError> {
+    if items.len() > MAX_BATCH_LEN {
+        return Err(NftError::Other(format!(
+            "batch of {} exceeds the maximum of {MAX_BATCH_LEN}",
+            items.len()
+        )));
+    }
+    for (to, metadata, _) in &items {
+        reject_reserved_principal(*to)?;
+        check_purposes(metadata)?;
+    }
+    let caller = runtime::caller();
     STATE.with(|state| {
-        let state = state.borrow();
-        let mut total = 0;
-        for collection in state.collections.values() {
-            total += collection.nfts.len()
+        let mut state = state.borrow_mut();
+        let collection = state
+            .collections
+            .get_mut(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        if !collection.has_role(&caller, Role::Minter) {
+            return Err(NftError::Unauthorized);
         }
-        total
+        collection.check_cycles()?;
+        collection.check_mint_window(&caller)?;
+        collection.check_supply(items.len())?;
+        let mut per_recipient: HashMap<Principal, u64> = HashMap::new();
+        for (to, _, _) in &items {
+            *per_recipient.entry(*to).or_default() += 1;
+        }
+        for (to, count) in &per_recipient {
+            collection.check_mint_limit(to, *count)?;
+        }
+        let mut hashes = Vec::with_capacity(items.len());
+        for (_, _, content) in &items {
+            let hash = collection.check_content(content)?;
+            if hash.is_some() && hashes.contains(&hash) {
+                return Err(NftError::Other("duplicate content".to_owned()));
+            }
+            hashes.push(hash);
+        }
+        let mut merged = Vec::with_capacity(items.len());
+        for (to, metadata, content) in items {
+            let metadata = collection.merge_metadata(metadata);
+            collection.check_schema(&metadata)?;
+            collection.check_content_size(&content, &metadata)?;
+            merged.push((to, metadata, content));
+        }
+        let mut minted = Vec::with_capacity(merged.len());
+        for ((to, metadata, content), hash) in merged.into_iter().zip(hashes) {
+            let token_id = collection.next_token_id();
+            NFTS.with(|nfts| {
+                nfts.borrow_mut().insert(
+                    nft_key(collection_id, token_id),
+                    Nft::new(token_id, to, metadata, content),
+                )
+            });
+            collection.reindex(token_id, None, Some(to));
+            collection.count_mint(to);
+            collection.track_content(hash);
+            minted.push((token_id, to));
+        }
+        for &(token_id, to) in &minted {
+            state.record(TxOp::Mint, collection_id, Some(token_id), None, Some(to));
+        }
+        Ok(minted.into_iter().map(|(token_id, _)| token_id).collect())
     })
 }
 
-#[query]
-fn total_supply_of_collection(collection_id: usize) -> Option<usize> {
-    STATE.with(|state| {
-        state
-            .borrow()
-            .collections
-            .get(&collection_id)
-            .map(|collection| collection.nfts.len())
-    })
+/// Primary-sale entry point: the token is minted straight to the buyer, so its
+/// history starts with a single Mint to `recipient` and no intermediate owner.
+#[update]
+fn mint_to(
+    collection_id: usize,
+    recipient: Principal,
+    metadata: Vec<MetadataPart>,
+    content: Vec<u8>,
+) -> Result<u64, NftError> {
+    mint(collection_id, recipient, metadata, content, None)
+}
+
+/// Trait values picked for `mint_random`: the trait at index i takes
+/// `values[seed[i] as usize % values.len()]`. Every list has a length that
+/// divides 256, so no value is favoured. Append new traits at the end only,
+/// or the traits of existing tokens can no longer be checked against their
+/// seed.
+const RANDOM_TRAITS: [(&str, &[&str]); 3] = [
+    ("board", &["walnut", "marble", "glass", "tournament"]),
+    ("pieces", &["staunton", "lewis", "bauhaus", "minimalist"]),
+    ("background", &["ivory", "charcoal", "crimson", "midnight"]),
+];
+
+fn random_traits(seed: &[u8]) -> MetadataPart {
+    MetadataPart {
+        purpose: MetadataPurpose::Rendered,
+        key_val_data: RANDOM_TRAITS
+            .iter()
+            .zip(seed)
+            .map(|((name, values), &byte)| {
+                let value = values[byte as usize % values.len()];
+                (name.to_string(), MetadataValue::Text(value.to_owned()))
+            })
+            .collect(),
+        data: Vec::new(),
+    }
 }
 
+/// Mints a token whose traits derive from fresh randomness. The seed is
+/// kept on the token so anyone can recompute the traits.
 #[update]
-fn insert_custodian_into_collection(collection_id: usize, custodian: Principal) -> bool {
+async fn mint_random(collection_id: usize, to: Principal) -> Result<u64, NftError> {
+    let caller = runtime::caller();
     STATE.with(|state| {
-        let mut state = state.borrow_mut();
+        let state = state.borrow();
         let collection = state
             .collections
-            .get_mut(&collection_id)
-            .expect("invalid collection id");
-        if collection.custodians.contains(&api::caller()) {
-            collection.custodians.insert(custodian)
+            .get(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        if collection.has_role(&caller, Role::Minter) {
+            Ok(())
         } else {
-            panic!("unauthorized")
+            Err(NftError::Unauthorized)
         }
-    })
+    })?;
+    let (seed,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .unwrap_or_else(|(_, err)| ic_cdk::trap(&format!("failed to get randomness: {err}")));
+    let token_id = mint(
+        collection_id,
+        to,
+        vec![random_traits(&seed)],
+        Vec::new(),
+        None,
+    )?;
+    let key = nft_key(collection_id, token_id);
+    NFTS.with(|nfts| {
+        let mut nfts = nfts.borrow_mut();
+        if let Some(mut nft) = nfts.get(&key) {
+            nft.random_seed = Some(seed);
+            nfts.insert(key, nft);
+        }
+    });
+    Ok(token_id)
 }
 
+/// Sets aside the next `count` ids for `claim_reserved`, so that a gated drop
+/// cannot be front-run for the low numbers.
 #[update]
-fn remove_custodian_from_collection(collection_id: usize, custodian: Principal) -> bool {
+fn reserve_tokens(collection_id: usize, count: u64) -> Result<Vec<u64>, NftError> {
+    if count > MAX_BATCH_LEN as u64 {
+        return Err(NftError::Other(format!(
+            "batch of {count} exceeds the maximum of {MAX_BATCH_LEN}"
+        )));
+    }
     STATE.with(|state| {
         let mut state = state.borrow_mut();
         let collection = state
             .collections
             .get_mut(&collection_id)
-            .expect("invalid collection id");
-        if collection.custodians.contains(&api::caller()) {
-            collection.custodians.remove(&custodian)
-        } else {
-            panic!("unauthorized")
+            .ok_or(NftError::InvalidCollection)?;
+        if !collection.has_role(&runtime::caller(), Role::Minter) {
+            return Err(NftError::Unauthorized);
         }
+        collection.check_supply(count as usize)?;
+        let reserved: Vec<u64> = (0..count).map(|_| collection.next_token_id()).collect();
+        collection.reserved.extend(&reserved);
+        Ok(reserved)
     })
 }
 
 #[query]
-fn is_custodian_of_collection(collection_id: usize, custodian: Principal) -> bool {
+fn reserved_tokens(collection_id: usize) -> Vec<u64> {
     STATE.with(|state| {
-        let state = state.borrow();
-        let collection = state
+        state
+            .borrow()
             .collections
             .get(&collection_id)
-            .expect("invalid collection id");
-        collection.custodians.contains(&custodian)
+            .map(|collection| collection.reserved.iter().copied().collect())
+            .unwrap_or_default()
     })
 }
 
+/// Gives up unclaimed reservations. Their ids are retired rather than reused,
+/// and stop counting towards the max supply.
 #[update]
-fn approve(collection_id: usize, token_id: u64, user: Principal) -> u128 {
-    let caller = api::caller();
+fn release_reserved(collection_id: usize, token_ids: Vec<u64>) -> Result<(), NftError> {
     STATE.with(|state| {
         let mut state = state.borrow_mut();
         let collection = state
             .collections
             .get_mut(&collection_id)
-            .expect("invalid collection id");
-        let nft = collection
-            .nfts
-            .get_mut(&token_id)
-            .expect("invalid token id");
-        if nft.owner != caller
-            && nft.approved != Some(caller)
-            && !collection
-                .operators
-                .get(&user)
-                .map(|operators| operators.contains(&caller))
-                .unwrap_or(false)
-            && !collection.custodians.contains(&caller)
+            .ok_or(NftError::InvalidCollection)?;
+        if !collection.has_role(&runtime::caller(), Role::Minter) {
+            Err(NftError::Unauthorized)
+        } else if let Some(token_id) = token_ids
+            .iter()
+            .find(|token_id| !collection.reserved.contains(token_id))
         {
-            panic!("unauthorized")
+            Err(NftError::Other(format!(
+                "token id {token_id} is not reserved"
+            )))
         } else {
-            nft.approved = Some(user);
-            state.next_txid()
-        }
+            for token_id in &token_ids {
+                if collection.reserved.remove(token_id) {
+                    collection.released += 1;
+                }
+            }
+            Ok(())
+        }
     })
 }
 
 #[update]
-fn set_approval_for_all(collection_id: usize, operator: Principal, is_approved: bool) -> u128 {
-    let caller = api::caller();
+fn set_claim_allowed(
+    collection_id: usize,
+    principal: Principal,
+    allowed: bool,
+) -> Result<(), NftError> {
     STATE.with(|state| {
         let mut state = state.borrow_mut();
         let collection = state
             .collections
             .get_mut(&collection_id)
-            .expect("invalid collection id");
-        if operator != caller {
-            let operators = collection.operators.entry(caller).or_default();
-            if operator == ANONYMOUS {
-                if !is_approved {
-                    operators.clear()
-                } else {
-                    // cannot enable everyone as an operator
-                }
-            } else if is_approved {
-                operators.insert(operator);
+            .ok_or(NftError::InvalidCollection)?;
+        if !collection.custodians.contains(&runtime::caller()) {
+            Err(NftError::Unauthorized)
+        } else if principal == ANONYMOUS {
+            Err(NftError::ZeroAddress)
+        } else {
+            if allowed {
+                collection.claim_allowlist.insert(principal);
             } else {
-                operators.remove(&operator);
+                collection.claim_allowlist.remove(&principal);
+            }
+            Ok(())
+        }
+    })
+}
+
+/// Mints a reserved id to the caller. Each allowlist entry is good for a
+/// single claim.
+#[update]
+fn claim_reserved(
+    collection_id: usize,
+    token_id: u64,
+    metadata: Vec<MetadataPart>,
+    content: Vec<u8>,
+) -> Result<(), NftError> {
+    check_purposes(&metadata)?;
+    let caller = runtime::caller();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let collection = state
+            .collections
+            .get_mut(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        if !collection.claim_allowlist.contains(&caller) {
+            return Err(NftError::Unauthorized);
+        }
+        if !collection.reserved.contains(&token_id) {
+            return Err(NftError::InvalidToken);
+        }
+        collection.check_paused()?;
+        collection.check_cycles()?;
+        collection.check_mint_window(&caller)?;
+        collection.check_mint_limit(&caller, 1)?;
+        let metadata = collection.merge_metadata(metadata);
+        collection.check_schema(&metadata)?;
+        collection.check_content_size(&content, &metadata)?;
+        let hash = collection.check_content(&content)?;
+        collection.reserved.remove(&token_id);
+        collection.claim_allowlist.remove(&caller);
+        NFTS.with(|nfts| {
+            nfts.borrow_mut().insert(
+                nft_key(collection_id, token_id),
+                Nft::new(token_id, caller, metadata, content),
+            )
+        });
+        collection.reindex(token_id, None, Some(caller));
+        collection.count_mint(caller);
+        collection.track_content(hash);
+        state.record(
+            TxOp::Mint,
+            collection_id,
+            Some(token_id),
+            None,
+            Some(caller),
+        );
+        Ok(())
+    })
+}
+
+#[update]
+fn begin_upload(collection_id: usize) -> Result<u64, NftError> {
+    let caller = runtime::caller();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let collection = state
+            .collections
+            .get(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        if collection.has_role(&caller, Role::Minter) {
+            collection.check_cycles()?;
+            let id = state.next_upload_id();
+            state.uploads.insert(
+                id,
+                Upload {
+                    collection_id,
+                    uploader: caller,
+                    content: Vec::new(),
+                    created: runtime::time(),
+                },
+            );
+            Ok(id)
+        } else {
+            Err(NftError::Unauthorized)
+        }
+    })
+}
+
+#[update]
+fn upload_chunk(upload_id: u64, chunk: Vec<u8>) -> Result<(), NftError> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = &mut *state;
+        let upload = state
+            .uploads
+            .get_mut(&upload_id)
+            .ok_or_else(|| NftError::Other("invalid upload id".to_owned()))?;
+        if let Some(collection) = state.collections.get(&upload.collection_id) {
+            collection.check_cycles()?;
+            if (upload.content.len() + chunk.len()) as u64 > collection.max_content_bytes() {
+                return Err(NftError::Other("content too large".to_owned()));
             }
         }
-        state.next_txid()
+        if upload.uploader != runtime::caller() {
+            Err(NftError::Unauthorized)
+        } else if upload.content.len() + chunk.len() > MAX_UPLOAD_SIZE {
+            Err(NftError::Other(format!(
+                "upload cannot exceed {MAX_UPLOAD_SIZE} bytes"
+            )))
+        } else {
+            upload.content.extend(chunk);
+            Ok(())
+        }
+    })
+}
+
+#[update]
+fn finalize_mint(
+    upload_id: u64,
+    to: Principal,
+    metadata: Vec<MetadataPart>,
+) -> Result<u64, NftError> {
+    let caller = runtime::caller();
+    let now = runtime::time();
+    let mut upload = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.uploads.retain(|id, upload| {
+            *id == upload_id || now.saturating_sub(upload.created) <= UPLOAD_TTL_NANOS
+        });
+        match state.uploads.get(&upload_id) {
+            None => Err(NftError::Other("invalid upload id".to_owned())),
+            Some(upload) if upload.uploader != caller => Err(NftError::Unauthorized),
+            Some(_) => Ok(state.uploads.remove(&upload_id).expect("upload exists")),
+        }
+    })?;
+    // a failed mint leaves the staged bytes in place, so the upload goes back
+    let result = mint_staged(
+        upload.collection_id,
+        to,
+        metadata,
+        &mut upload.content,
+        None,
+    );
+    if result.is_err() {
+        STATE.with(|state| state.borrow_mut().uploads.insert(upload_id, upload));
+    }
+    result
+}
+
+#[update]
+fn set_name_of_collection(collection_id: usize, name: String) -> Result<(), NftError> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let collection = state
+            .collections
+            .get_mut(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        if collection.custodians.contains(&runtime::caller()) {
+            validate_label("name", &name, MAX_NAME_LEN)?;
+            collection.name = name;
+            Ok(())
+        } else {
+            Err(NftError::Unauthorized)
+        }
+    })
+}
+
+#[update]
+fn set_symbol_of_collection(collection_id: usize, symbol: String) -> Result<(), NftError> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let collection = state
+            .collections
+            .get_mut(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        if collection.custodians.contains(&runtime::caller()) {
+            validate_label("symbol", &symbol, MAX_SYMBOL_LEN)?;
+            collection.symbol = symbol;
+            Ok(())
+        } else {
+            Err(NftError::Unauthorized)
+        }
+    })
+}
+
+/// Tags are trimmed and lowercased, and repeats dropped.
+#[update]
+fn set_tags(collection_id: usize, tags: Vec<String>) -> Result<(), NftError> {
+    let mut normalized: Vec<String> = Vec::new();
+    for tag in tags {
+        validate_label("tag", &tag, MAX_TAG_LEN)?;
+        let tag = tag.trim().to_lowercase();
+        if !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
+    }
+    if normalized.len() > MAX_TAGS {
+        return Err(NftError::Other(format!(
+            "a collection can have at most {MAX_TAGS} tags"
+        )));
+    }
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let collection = state
+            .collections
+            .get_mut(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        if collection.custodians.contains(&runtime::caller()) {
+            collection.tags = normalized;
+            Ok(())
+        } else {
+            Err(NftError::Unauthorized)
+        }
     })
 }
 
 #[query]
-fn is_approved_for_all(collection_id: usize, operator: Principal) -> bool {
+fn collections_by_tag(tag: String) -> Vec<usize> {
+    let tag = tag.trim().to_lowercase();
     STATE.with(|state| {
-        let state = state.borrow();
+        let mut collection_ids: Vec<_> = state
+            .borrow()
+            .collections
+            .iter()
+            .filter(|(_, collection)| collection.tags.contains(&tag))
+            .map(|(&id, _)| id)
+            .collect();
+        collection_ids.sort();
+        collection_ids
+    })
+}
+
+#[update]
+fn set_logo_of_collection(collection_id: usize, logo: Logo) -> Result<(), NftError> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let collection = state
+            .collections
+            .get_mut(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        if collection.custodians.contains(&runtime::caller()) {
+            logo.validate(collection.max_logo_size())?;
+            collection.set_logo(logo);
+            Ok(())
+        } else {
+            Err(NftError::Unauthorized)
+        }
+    })
+}
+
+#[update]
+fn set_default_metadata(collection_id: usize, parts: Vec<MetadataPart>) -> Result<(), NftError> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let collection = state
+            .collections
+            .get_mut(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        if collection.has_role(&runtime::caller(), Role::MetadataEditor) {
+            check_purposes(&parts)?;
+            collection.default_metadata = parts;
+            Ok(())
+        } else {
+            Err(NftError::Unauthorized)
+        }
+    })
+}
+
+/// Replaces the keys every token must carry. Existing tokens are not
+/// rechecked, but any later edit to their metadata has to satisfy the schema.
+#[update]
+fn set_metadata_schema(
+    collection_id: usize,
+    required: Vec<(String, MetadataValueKind)>,
+) -> Result<(), NftError> {
+    if required.len() > MAX_REQUIRED_KEYS {
+        return Err(NftError::Other(format!(
+            "cannot require more than {MAX_REQUIRED_KEYS} keys"
+        )));
+    }
+    for (i, (key, _)) in required.iter().enumerate() {
+        if required[..i].iter().any(|(other, _)| other == key) {
+            return Err(NftError::Other(format!("key {key} is required twice")));
+        }
+    }
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
         let collection = state
+            .collections
+            .get_mut(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        if collection.custodians.contains(&runtime::caller()) {
+            collection.required_metadata_keys = required;
+            Ok(())
+        } else {
+            Err(NftError::Unauthorized)
+        }
+    })
+}
+
+#[query]
+fn metadata_schema(collection_id: usize) -> Vec<(String, MetadataValueKind)> {
+    STATE.with(|state| {
+        state
+            .borrow()
             .collections
             .get(&collection_id)
-            .expect("invalid collection id");
-        collection
-            .operators
-            .get(&api::caller())
-            .map(|s| s.contains(&operator))
-            .unwrap_or(false)
+            .map(|collection| collection.required_metadata_keys.clone())
+            .unwrap_or_default()
+    })
+}
+
+/// The metadata `mint` would store for `metadata` once the collection
+/// defaults are merged in; pass no parts to read the defaults themselves.
+#[query]
+fn effective_metadata(
+    collection_id: usize,
+    metadata: Vec<MetadataPart>,
+) -> Option<Vec<MetadataPart>> {
+    STATE.with(|state| {
+        state
+            .borrow()
+            .collections
+            .get(&collection_id)
+            .map(|collection| collection.merge_metadata(metadata))
+    })
+}
+
+#[update]
+fn set_paused(collection_id: usize, paused: bool) -> Result<(), NftError> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let collection = state
+            .collections
+            .get_mut(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        if collection.has_role(&runtime::caller(), Role::Pauser) {
+            collection.paused = paused;
+            Ok(())
+        } else {
+            Err(NftError::Unauthorized)
+        }
+    })
+}
+
+#[update]
+fn set_transferable(collection_id: usize, transferable: bool) -> Result<(), NftError> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let collection = state
+            .collections
+            .get_mut(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        if collection.custodians.contains(&runtime::caller()) {
+            collection.soulbound = !transferable;
+            Ok(())
+        } else {
+            Err(NftError::Unauthorized)
+        }
+    })
+}
+
+#[query]
+fn is_transferable(collection_id: usize) -> bool {
+    STATE.with(|state| {
+        state
+            .borrow()
+            .collections
+            .get(&collection_id)
+            .is_some_and(|collection| !collection.soulbound)
+    })
+}
+
+#[query]
+fn is_paused(collection_id: usize) -> bool {
+    STATE.with(|state| {
+        state
+            .borrow()
+            .collections
+            .get(&collection_id)
+            .is_some_and(|collection| collection.paused)
+    })
+}
+
+#[update]
+fn set_max_supply(collection_id: usize, max: u64) -> Result<(), NftError> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let collection = state
+            .collections
+            .get_mut(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        if !collection.custodians.contains(&runtime::caller()) {
+            Err(NftError::Unauthorized)
+        } else if max < collection.allocated_supply() {
+            Err(NftError::Other(format!(
+                "max supply {max} is below the current supply of {}",
+                collection.allocated_supply()
+            )))
+        } else {
+            collection.max_supply = Some(max);
+            Ok(())
+        }
+    })
+}
+
+#[update]
+fn set_mint_limit(collection_id: usize, limit: Option<u64>) -> Result<(), NftError> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let collection = state
+            .collections
+            .get_mut(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        if collection.custodians.contains(&runtime::caller()) {
+            collection.mint_limit_per_principal = limit;
+            Ok(())
+        } else {
+            Err(NftError::Unauthorized)
+        }
+    })
+}
+
+/// Either bound may be left open; the window includes `start` and ends just
+/// before `end`.
+#[update]
+fn set_mint_window(
+    collection_id: usize,
+    start: Option<u64>,
+    end: Option<u64>,
+) -> Result<(), NftError> {
+    if let (Some(start), Some(end)) = (start, end) {
+        if start >= end {
+            return Err(NftError::Other(
+                "mint window must start before it ends".to_owned(),
+            ));
+        }
+    }
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let collection = state
+            .collections
+            .get_mut(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        if collection.custodians.contains(&runtime::caller()) {
+            collection.mint_start = start;
+            collection.mint_end = end;
+            Ok(())
+        } else {
+            Err(NftError::Unauthorized)
+        }
+    })
+}
+
+#[update]
+fn reset_mint_count(collection_id: usize, principal: Principal) -> Result<(), NftError> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let collection = state
+            .collections
+            .get_mut(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        if collection.custodians.contains(&runtime::caller()) {
+            collection.mint_counts.remove(&principal);
+            Ok(())
+        } else {
+            Err(NftError::Unauthorized)
+        }
+    })
+}
+
+#[query]
+fn mints_by(collection_id: usize, principal: Principal) -> u64 {
+    STATE.with(|state| {
+        state
+            .borrow()
+            .collections
+            .get(&collection_id)
+            .map(|collection| collection.mints_by(&principal))
+            .unwrap_or_default()
+    })
+}
+
+#[update]
+fn set_max_logo_size(collection_id: usize, max_size: usize) -> Result<(), NftError> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let collection = state
+            .collections
+            .get_mut(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        if collection.custodians.contains(&runtime::caller()) {
+            collection.max_logo_size = Some(max_size);
+            Ok(())
+        } else {
+            Err(NftError::Unauthorized)
+        }
     })
 }
 
+/// `None` falls back to the canister-wide default. A limit has to be
+/// between 1 byte and `MAX_UPLOAD_SIZE`.
 #[update]
-fn burn(collection_id: usize, token_id: u64) -> u128 {
+fn set_max_content_bytes(collection_id: usize, max_bytes: Option<u64>) -> Result<(), NftError> {
     STATE.with(|state| {
         let mut state = state.borrow_mut();
         let collection = state
             .collections
             .get_mut(&collection_id)
-            .expect("invalid token id");
-        let nft = collection
-            .nfts
-            .get_mut(&token_id)
-            .expect("invalid token id");
-        if nft.owner != api::caller() {
-            panic!("unauthorized")
+            .ok_or(NftError::InvalidCollection)?;
+        if !collection.custodians.contains(&runtime::caller()) {
+            Err(NftError::Unauthorized)
+        } else if max_bytes
+            .is_some_and(|max_bytes| max_bytes == 0 || max_bytes > MAX_UPLOAD_SIZE as u64)
+        {
+            Err(NftError::Other(format!(
+                "content limit must be between 1 and {MAX_UPLOAD_SIZE} bytes"
+            )))
         } else {
-            nft.owner = ANONYMOUS;
-            state.next_txid()
+            collection.max_content_bytes = max_bytes;
+            Ok(())
         }
     })
 }
+
+/// The limit in force, which is the canister-wide default unless the
+/// collection set its own.
+#[query]
+fn max_content_bytes(collection_id: usize) -> Option<u64> {
+    STATE.with(|state| {
+        state
+            .borrow()
+            .collections
+            .get(&collection_id)
+            .map(Collection::max_content_bytes)
+    })
+}
+
+#[update]
+fn set_royalty(collection_id: usize, bps: u16, recipient: Principal) -> Result<(), NftError> {
+    if bps > MAX_ROYALTY_BPS {
+        return Err(NftError::Other(format!(
+            "royalty cannot exceed {MAX_ROYALTY_BPS} bps"
+        )));
+    }
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let collection = state
+            .collections
+            .get_mut(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        if collection.custodians.contains(&runtime::caller()) {
+            collection.royalty_bps = bps;
+            collection.royalty_recipient = Some(recipient);
+            Ok(())
+        } else {
+            Err(NftError::Unauthorized)
+        }
+    })
+}
+
+/// This canister holds no funds: marketplaces read the fee, pay it to the
+/// treasury themselves and then transfer. Each transfer records the fee owed.
+#[update]
+fn set_transfer_fee(
+    collection_id: usize,
+    fee: u128,
+    treasury: Option<Principal>,
+) -> Result<(), NftError> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let collection = state
+            .collections
+            .get_mut(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        if !collection.custodians.contains(&runtime::caller()) {
+            Err(NftError::Unauthorized)
+        } else if fee > 0 && treasury.is_none() {
+            Err(NftError::Other(
+                "a transfer fee needs a treasury to be paid to".to_owned(),
+            ))
+        } else {
+            collection.transfer_fee = fee;
+            collection.treasury = treasury;
+            Ok(())
+        }
+    })
+}
+
+#[query]
+fn transfer_fee_of(collection_id: usize) -> u128 {
+    STATE.with(|state| {
+        state
+            .borrow()
+            .collections
+            .get(&collection_id)
+            .map(|collection| collection.transfer_fee)
+            .unwrap_or_default()
+    })
+}
+
+#[query]
+fn treasury_of(collection_id: usize) -> Option<Principal> {
+    STATE.with(|state| {
+        state
+            .borrow()
+            .collections
+            .get(&collection_id)
+            .and_then(|collection| collection.treasury)
+    })
+}
+
+#[query]
+fn royalty_for_sale(collection_id: usize, sale_price: u128) -> Result<(Principal, u128), NftError> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let collection = state
+            .collections
+            .get(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        let recipient = collection
+            .royalty_recipient
+            .ok_or_else(|| NftError::Other("royalty is not configured".to_owned()))?;
+        let bps = collection.royalty_bps as u128;
+        let denominator = MAX_ROYALTY_BPS as u128;
+        // split the multiplication so large sale prices cannot overflow
+        let amount = sale_price / denominator * bps + sale_price % denominator * bps / denominator;
+        Ok((recipient, amount))
+    })
+}
+
+#[query]
+fn name_of_collection(collection_id: usize) -> Option<String> {
+    STATE.with(|state| {
+        state
+            .borrow()
+            .collections
+            .get(&collection_id)
+            .map(|collection| collection.name.to_owned())
+    })
+}
+
+#[query]
+fn symbol_of_collection(collection_id: usize) -> Option<String> {
+    STATE.with(|state| {
+        state
+            .borrow()
+            .collections
+            .get(&collection_id)
+            .map(|collection| collection.symbol.to_owned())
+    })
+}
+
+#[query]
+fn logo_of_collection(collection_id: usize) -> Option<Logo> {
+    STATE.with(|state| {
+        state
+            .borrow()
+            .collections
+            .get(&collection_id)
+            .map(|collection| collection.logo.to_owned())
+    })
+}
+
+/// The extension alone, for rendering decisions that do not need the bytes.
+#[query]
+fn logo_extension(collection_id: usize) -> Option<LogoExtension> {
+    STATE.with(|state| {
+        state
+            .borrow()
+            .collections
+            .get(&collection_id)
+            .map(|collection| collection.logo.extension.clone())
+    })
+}
+
+#[query]
+fn has_logo(collection_id: usize) -> bool {
+    STATE.with(|state| {
+        state
+            .borrow()
+            .collections
+            .get(&collection_id)
+            .is_some_and(|collection| !collection.logo.data.is_empty())
+    })
+}
+
+#[query]
+fn logo_hash(collection_id: usize) -> Option<String> {
+    STATE.with(|state| {
+        state
+            .borrow()
+            .collections
+            .get(&collection_id)
+            .map(|collection| collection.logo_hash.to_owned())
+    })
+}
+
+#[query]
+fn balance_of_user(collection_id: usize, principal: Principal) -> usize {
+    STATE.with(|state| {
+        state
+            .borrow()
+            .collections
+            .get(&collection_id)
+            .map(|collection| collection.balance_of(&principal))
+            .unwrap_or_default()
+    })
+}
+
+/// The `n` largest holders, most tokens first; holders with equal balances
+/// are ordered by principal bytes so pages are stable between calls.
+#[query]
+fn top_holders(collection_id: usize, n: usize) -> Vec<(Principal, usize)> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let Some(collection) = state.collections.get(&collection_id) else {
+            return vec![];
+        };
+        let mut holders: Vec<(Principal, usize)> = collection
+            .owner_index
+            .iter()
+            .filter(|(_, tokens)| !tokens.is_empty())
+            .map(|(&owner, tokens)| (owner, tokens.len()))
+            .collect();
+        holders.sort_unstable_by(|(a, a_count), (b, b_count)| {
+            b_count
+                .cmp(a_count)
+                .then_with(|| a.as_slice().cmp(b.as_slice()))
+        });
+        holders.truncate(n.min(MAX_LIMIT));
+        holders
+    })
+}
+
+#[query]
+fn total_balance_of(principal: Principal) -> usize {
+    STATE.with(|state| {
+        state
+            .borrow()
+            .collections
+            .values()
+            .map(|collection| collection.balance_of(&principal))
+            .sum()
+    })
+}
+
+#[query]
+fn collections_owning(principal: Principal) -> Vec<usize> {
+    STATE.with(|state| {
+        let mut collection_ids: Vec<_> = state
+            .borrow()
+            .collections
+            .iter()
+            .filter(|(_, collection)| collection.balance_of(&principal) > 0)
+            .map(|(&id, _)| id)
+            .collect();
+        collection_ids.sort();
+        collection_ids
+    })
+}
+
+#[query]
+fn owner_of_nft(collection_id: usize, token_id: u64) -> Option<Principal> {
+    NFTS.with(|nfts| {
+        nfts.borrow()
+            .get(&nft_key(collection_id, token_id))
+            .map(|nft| nft.owner)
+    })
+}
+
+/// Owners aligned with `token_ids`, `None` where a token does not exist.
+/// Traps on more than `MAX_LIMIT` ids, since truncating would break alignment.
+#[query]
+fn owners_of(collection_id: usize, token_ids: Vec<u64>) -> Vec<Option<Principal>> {
+    if token_ids.len() > MAX_LIMIT {
+        ic_cdk::trap(&format!("at most {MAX_LIMIT} token ids per call"));
+    }
+    NFTS.with(|nfts| {
+        let nfts = nfts.borrow();
+        token_ids
+            .into_iter()
+            .map(|token_id| {
+                nfts.get(&nft_key(collection_id, token_id))
+                    .map(|nft| nft.owner)
+            })
+            .collect()
+    })
+}
+
+/// Everything about a token in one call. Content is only filled in when
+/// asked for, since it can run to megabytes.
+#[derive(CandidType)]
+pub struct NftView {
+    id: u64,
+    owner: Principal,
+    approved: Option<Principal>,
+    metadata: Vec<MetadataPart>,
+    content: Option<Vec<u8>>,
+    pgn: Option<String>,
+    fen: Option<String>,
+    locked_until: Option<u64>,
+    minted_at: u64,
+    set_id: Option<u64>,
+    random_seed: Option<Vec<u8>>,
+    offered_to: Option<Principal>,
+}
+
+#[query]
+fn nft_info(collection_id: usize, token_id: u64, include_content: bool) -> Option<NftView> {
+    let nft = NFTS.with(|nfts| nfts.borrow().get(&nft_key(collection_id, token_id)))?;
+    Some(NftView {
+        id: nft.id,
+        owner: nft.owner,
+        approved: nft.approved_principal(),
+        metadata: nft.metadata,
+        content: include_content.then_some(nft.content),
+        pgn: nft.pgn,
+        fen: nft.fen,
+        locked_until: nft.locked_until,
+        minted_at: nft.minted_at,
+        set_id: nft.set_id,
+        random_seed: nft.random_seed,
+        offered_to: nft.offered_to,
+    })
+}
+
+#[query]
+fn metadata_of_nft(collection_id: usize, token_id: u64) -> Option<Vec<MetadataPart>> {
+    NFTS.with(|nfts| {
+        nfts.borrow()
+            .get(&nft_key(collection_id, token_id))
+            .map(|nft| nft.metadata)
+    })
+}
+
+#[query]
+fn preview_of_nft(collection_id: usize, token_id: u64) -> Option<Vec<u8>> {
+    NFTS.with(|nfts| nfts.borrow().get(&nft_key(collection_id, token_id)))?
+        .metadata
+        .into_iter()
+        .find(|part| part.purpose == MetadataPurpose::Preview)
+        .map(|part| part.data)
+}
+
+#[query]
+fn content_of_nft(collection_id: usize, token_id: u64) -> Option<Vec<u8>> {
+    NFTS.with(|nfts| {
+        nfts.borrow()
+            .get(&nft_key(collection_id, token_id))
+            .map(|nft| nft.content)
+    })
+}
+
+#[query]
+fn content_chunk(collection_id: usize, token_id: u64, offset: u64, len: u64) -> Option<Vec<u8>> {
+    NFTS.with(|nfts| {
+        nfts.borrow()
+            .get(&nft_key(collection_id, token_id))
+            .map(|nft| {
+                nft.content
+                    .into_iter()
+                    .skip(offset as usize)
+                    .take(len as usize)
+                    .collect()
+            })
+    })
+}
+
+#[query]
+fn find_tokens_by_metadata(collection_id: usize, key: String, value: MetadataValue) -> Vec<u64> {
+    NFTS.with(|nfts| {
+        // the range scan yields token ids in ascending order
+        nfts_of_collection(&nfts.borrow(), collection_id)
+            .filter(|nft| {
+                nft.metadata
+                    .iter()
+                    .any(|part| part.key_val_data.get(&key) == Some(&value))
+            })
+            .map(|nft| nft.id)
+            .collect()
+    })
+}
+
+/// A metadata value as a trait for rarity: naturals of different widths are
+/// the same trait, as they are for `find_tokens_by_metadata`.
+#[derive(PartialEq, Eq, Hash)]
+enum TraitValue {
+    Text(String),
+    Blob(Vec<u8>),
+    Nat(u128),
+}
+
+impl From<MetadataValue> for TraitValue {
+    fn from(value: MetadataValue) -> Self {
+        match value {
+            MetadataValue::Text(text) => TraitValue::Text(text),
+            MetadataValue::Blob(blob) => TraitValue::Blob(blob),
+            MetadataValue::Nat8(n) => TraitValue::Nat(n.into()),
+            MetadataValue::Nat16(n) => TraitValue::Nat(n.into()),
+            MetadataValue::Nat32(n) => TraitValue::Nat(n.into()),
+            MetadataValue::Nat64(n) => TraitValue::Nat(n.into()),
+            MetadataValue::Nat(n) => TraitValue::Nat(n),
+        }
+    }
+}
+
+/// Each token scores the sum, over its traits, of the token count divided by
+/// the number of tokens sharing that trait, so rarer traits weigh more. A key
+/// set in several parts counts once, with the value `metadata_value` reports.
+/// Best effort: every call scans the whole collection, and as a query it
+/// cannot cache the result, so large collections may run out of
+/// instructions. Highest score first, ties in token id order, at most
+/// `MAX_LIMIT` per page.
+#[query]
+fn rarity_scores(collection_id: usize, offset: usize, limit: usize) -> Vec<(u64, u64)> {
+    let tokens: Vec<(u64, Vec<(String, TraitValue)>)> = NFTS.with(|nfts| {
+        nfts_of_collection(&nfts.borrow(), collection_id)
+            .map(|nft| {
+                let mut traits: HashMap<String, MetadataValue> = HashMap::new();
+                for part in nft.metadata {
+                    for (key, value) in part.key_val_data {
+                        traits.entry(key).or_insert(value);
+                    }
+                }
+                let traits = traits
+                    .into_iter()
+                    .map(|(key, value)| (key, value.into()))
+                    .collect();
+                (nft.id, traits)
+            })
+            .collect()
+    });
+    let mut counts: HashMap<&(String, TraitValue), u64> = HashMap::new();
+    for (_, traits) in &tokens {
+        for trait_ in traits {
+            *counts.entry(trait_).or_default() += 1;
+        }
+    }
+    let total = tokens.len() as u64;
+    let mut scores: Vec<(u64, u64)> = tokens
+        .iter()
+        .map(|(token_id, traits)| {
+            let score = traits
+                .iter()
+                .map(|trait_| total.saturating_mul(RARITY_SCALE) / counts[trait_])
+                .fold(0, u64::saturating_add);
+            (*token_id, score)
+        })
+        .collect();
+    scores.sort_unstable_by(|(a, a_score), (b, b_score)| b_score.cmp(a_score).then(a.cmp(b)));
+    scores
+        .into_iter()
+        .skip(offset)
+        .take(limit.min(MAX_LIMIT))
+        .collect()
+}
+
+/// The token's metadata as deterministic JSON, without its content. Also
+/// served over HTTP at `/collection/{id}/token/{id}/metadata`.
+#[query]
+fn token_metadata_json(collection_id: usize, token_id: u64) -> Option<String> {
+    NFTS.with(|nfts| nfts.borrow().get(&nft_key(collection_id, token_id)))
+        .map(|nft| json::token_metadata(collection_id, token_id, &nft.metadata))
+}
+
+/// The value of `key` in the first metadata part that sets it.
+#[query]
+fn metadata_value(collection_id: usize, token_id: u64, key: String) -> Option<MetadataValue> {
+    NFTS.with(|nfts| nfts.borrow().get(&nft_key(collection_id, token_id)))?
+        .metadata
+        .into_iter()
+        .find_map(|mut part| part.key_val_data.remove(&key))
+}
+
+#[update]
+fn add_metadata_part(
+    collection_id: usize,
+    token_id: u64,
+    part: MetadataPart,
+) -> Result<(), NftError> {
+    update_metadata(collection_id, token_id, |metadata| metadata.push(part))
+}
+
+#[update]
+fn replace_metadata(
+    collection_id: usize,
+    token_id: u64,
+    metadata: Vec<MetadataPart>,
+) -> Result<(), NftError> {
+    update_metadata(collection_id, token_id, |current| *current = metadata)
+}
+
+/// Sets one key in the part of the given purpose, adding the part if the
+/// token has none.
+#[update]
+fn set_metadata_value(
+    collection_id: usize,
+    token_id: u64,
+    purpose: MetadataPurpose,
+    key: String,
+    value: MetadataValue,
+) -> Result<(), NftError> {
+    update_metadata(collection_id, token_id, |metadata| {
+        match metadata.iter_mut().find(|part| part.purpose == purpose) {
+            Some(part) => {
+                part.key_val_data.insert(key, value);
+            }
+            None => metadata.push(MetadataPart {
+                purpose,
+                key_val_data: HashMap::from([(key, value)]),
+                data: Vec::new(),
+            }),
+        }
+    })
+}
+
+fn update_metadata(
+    collection_id: usize,
+    token_id: u64,
+    f: impl FnOnce(&mut Vec<MetadataPart>),
+) -> Result<(), NftError> {
+    let caller = runtime::caller();
+    STATE.with(|state| {
+        let state = state.borrow();
+        let collection = state
+            .collections
+            .get(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        let key = nft_key(collection_id, token_id);
+        let mut nft = NFTS
+            .with(|nfts| nfts.borrow().get(&key))
+            .ok_or(NftError::InvalidToken)?;
+        if nft.owner != caller && !collection.has_role(&caller, Role::MetadataEditor) {
+            Err(NftError::Unauthorized)
+        } else {
+            f(&mut nft.metadata);
+            check_purposes(&nft.metadata)?;
+            collection.check_schema(&nft.metadata)?;
+            collection.check_content_size(&nft.content, &nft.metadata)?;
+            NFTS.with(|nfts| nfts.borrow_mut().insert(key, nft));
+            Ok(())
+        }
+    })
+}
+
+#[update]
+fn attach_pgn(collection_id: usize, token_id: u64, pgn: String) -> Result<(), NftError> {
+    if !STATE.with(|state| state.borrow().collections.contains_key(&collection_id)) {
+        return Err(NftError::InvalidCollection);
+    }
+    let key = nft_key(collection_id, token_id);
+    let mut nft = NFTS
+        .with(|nfts| nfts.borrow().get(&key))
+        .ok_or(NftError::InvalidToken)?;
+    if nft.owner != runtime::caller() {
+        Err(NftError::Unauthorized)
+    } else {
+        chess::parse_pgn(&pgn).map_err(NftError::Other)?;
+        if let Some(fen) = &nft.fen {
+            let position = chess::Position::from_fen(fen).map_err(NftError::Other)?;
+            check_final_position(&pgn, &position)?;
+        }
+        nft.pgn = Some(pgn);
+        NFTS.with(|nfts| nfts.borrow_mut().insert(key, nft));
+        Ok(())
+    }
+}
+
+#[query]
+fn pgn_of_nft(collection_id: usize, token_id: u64) -> Option<String> {
+    NFTS.with(|nfts| {
+        nfts.borrow()
+            .get(&nft_key(collection_id, token_id))
+            .and_then(|nft| nft.pgn)
+    })
+}
+
+#[update]
+fn set_final_position(collection_id: usize, token_id: u64, fen: String) -> Result<(), NftError> {
+    if !STATE.with(|state| state.borrow().collections.contains_key(&collection_id)) {
+        return Err(NftError::InvalidCollection);
+    }
+    let key = nft_key(collection_id, token_id);
+    let mut nft = NFTS
+        .with(|nfts| nfts.borrow().get(&key))
+        .ok_or(NftError::InvalidToken)?;
+    if nft.owner != runtime::caller() {
+        Err(NftError::Unauthorized)
+    } else {
+        let position = chess::Position::from_fen(&fen).map_err(NftError::Other)?;
+        if let Some(pgn) = &nft.pgn {
+            check_final_position(pgn, &position)?;
+        }
+        nft.fen = Some(fen);
+        NFTS.with(|nfts| nfts.borrow_mut().insert(key, nft));
+        Ok(())
+    }
+}
+
+/// A token's PGN and FEN, when both are set, describe the same game.
+fn check_final_position(pgn: &str, position: &chess::Position) -> Result<(), NftError> {
+    let final_position = chess::parse_pgn(pgn)
+        .and_then(|pgn| chess::replay(&pgn))
+        .map_err(NftError::Other)?;
+    if final_position.same_position(position) {
+        Ok(())
+    } else {
+        Err(NftError::Other(format!(
+            "fen does not match the final position of the pgn: {}",
+            final_position.to_fen()
+        )))
+    }
+}
+
+#[query]
+fn fen_of_nft(collection_id: usize, token_id: u64) -> Option<String> {
+    NFTS.with(|nfts| {
+        nfts.borrow()
+            .get(&nft_key(collection_id, token_id))
+            .and_then(|nft| nft.fen)
+    })
+}
+
+#[query]
+fn content_hash(collection_id: usize, token_id: u64) -> Option<[u8; 32]> {
+    NFTS.with(|nfts| {
+        nfts.borrow()
+            .get(&nft_key(collection_id, token_id))
+            .map(|nft| nft.hash())
+    })
+}
+
+/// `None` for unknown tokens and for tokens minted before hashes were kept.
+#[query]
+fn is_content_authentic(collection_id: usize, token_id: u64) -> Option<bool> {
+    NFTS.with(|nfts| {
+        let nft = nfts.borrow().get(&nft_key(collection_id, token_id))?;
+        nft.minted_hash.map(|minted_hash| minted_hash == nft.hash())
+    })
+}
+
+/// Turning uniqueness on hashes the content already minted, and fails if
+/// the collection already holds two tokens with the same content.
+#[update]
+fn set_unique_content(collection_id: usize, enabled: bool) -> Result<(), NftError> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let collection = state
+            .collections
+            .get_mut(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        if !collection.custodians.contains(&runtime::caller()) {
+            return Err(NftError::Unauthorized);
+        }
+        let mut hashes = HashSet::new();
+        if enabled {
+            let unique = NFTS.with(|nfts| {
+                nfts_of_collection(&nfts.borrow(), collection_id)
+                    .all(|nft| hashes.insert(Sha256::digest(&nft.content).into()))
+            });
+            if !unique {
+                return Err(NftError::Other(
+                    "collection already holds duplicate content".to_owned(),
+                ));
+            }
+        }
+        collection.unique_content = enabled;
+        collection.content_hashes = hashes;
+        Ok(())
+    })
+}
+
+/// `hash` is the SHA-256 of the content bytes alone, unlike `content_hash`
+/// which also covers the metadata. Always false unless the collection
+/// enforces unique content.
+#[query]
+fn content_exists(collection_id: usize, hash: [u8; 32]) -> bool {
+    STATE.with(|state| {
+        state
+            .borrow()
+            .collections
+            .get(&collection_id)
+            .is_some_and(|collection| collection.content_hashes.contains(&hash))
+    })
+}
+
+#[query]
+fn ownership_history(collection_id: usize, token_id: u64) -> Vec<(Principal, u64)> {
+    NFTS.with(|nfts| {
+        nfts.borrow()
+            .get(&nft_key(collection_id, token_id))
+            .map(|nft| nft.owner_history)
+            .unwrap_or_default()
+    })
+}
+
+#[update]
+fn lock_token(collection_id: usize, token_id: u64, until_ns: u64) -> Result<(), NftError> {
+    if !STATE.with(|state| state.borrow().collections.contains_key(&collection_id)) {
+        return Err(NftError::InvalidCollection);
+    }
+    let key = nft_key(collection_id, token_id);
+    let mut nft = NFTS
+        .with(|nfts| nfts.borrow().get(&key))
+        .ok_or(NftError::InvalidToken)?;
+    if nft.owner != runtime::caller() {
+        Err(NftError::Unauthorized)
+    } else if nft.is_locked() && nft.locked_until > Some(until_ns) {
+        // an active lock can be extended, never cut short
+        Err(NftError::Locked)
+    } else {
+        nft.locked_until = Some(until_ns);
+        NFTS.with(|nfts| nfts.borrow_mut().insert(key, nft));
+        Ok(())
+    }
+}
+
+#[query]
+fn is_locked(collection_id: usize, token_id: u64) -> bool {
+    NFTS.with(|nfts| {
+        nfts.borrow()
+            .get(&nft_key(collection_id, token_id))
+            .is_some_and(|nft| nft.is_locked())
+    })
+}
+
+/// Groups a token into a set such as a puzzle pack, or takes it out of its
+/// set when `set_id` is `None`.
+#[update]
+fn assign_to_set(collection_id: usize, token_id: u64, set_id: Option<u64>) -> Result<(), NftError> {
+    let caller = runtime::caller();
+    let is_custodian = STATE.with(|state| {
+        state
+            .borrow()
+            .collections
+            .get(&collection_id)
+            .map(|collection| collection.custodians.contains(&caller))
+            .ok_or(NftError::InvalidCollection)
+    })?;
+    let key = nft_key(collection_id, token_id);
+    let mut nft = NFTS
+        .with(|nfts| nfts.borrow().get(&key))
+        .ok_or(NftError::InvalidToken)?;
+    if nft.owner != caller && !is_custodian {
+        Err(NftError::Unauthorized)
+    } else {
+        nft.set_id = set_id;
+        NFTS.with(|nfts| nfts.borrow_mut().insert(key, nft));
+        Ok(())
+    }
+}
+
+#[query]
+fn tokens_in_set(collection_id: usize, set_id: u64) -> Vec<u64> {
+    NFTS.with(|nfts| {
+        nfts_of_collection(&nfts.borrow(), collection_id)
+            .filter(|nft| nft.set_id == Some(set_id))
+            .map(|nft| nft.id)
+            .collect()
+    })
+}
+
+#[query]
+fn set_complete(collection_id: usize, set_id: u64, expected_size: u64) -> bool {
+    tokens_in_set(collection_id, set_id).len() as u64 == expected_size
+}
+
+/// Whether `owner` holds every token of a set of `expected_size` tokens.
+#[query]
+fn owns_complete_set(
+    collection_id: usize,
+    set_id: u64,
+    expected_size: u64,
+    owner: Principal,
+) -> bool {
+    let tokens = tokens_in_set(collection_id, set_id);
+    tokens.len() as u64 == expected_size
+        && STATE.with(|state| {
+            state
+                .borrow()
+                .collections
+                .get(&collection_id)
+                .and_then(|collection| collection.owner_index.get(&owner))
+                .is_some_and(|owned| tokens.iter().all(|token_id| owned.contains(token_id)))
+        })
+}
+
+/// Canister recipients are notified through `on_nft_received`, as they are
+/// for every other kind of transfer. Transfers with `from == to` would change
+/// nothing and are rejected rather than logged.
+#[update]
+fn transfer_from_to(
+    collection_id: usize,
+    token_id: u64,
+    from: Principal,
+    to: Principal,
+    idempotency_key: Option<[u8; 32]>,
+) -> Result<u128, NftError> {
+    transfer(collection_id, token_id, from, to, None, idempotency_key)
+}
+
+#[update]
+fn transfer_from_to_with_memo(
+    collection_id: usize,
+    token_id: u64,
+    from: Principal,
+    to: Principal,
+    memo: Vec<u8>,
+) -> Result<u128, NftError> {
+    if memo.len() > MAX_MEMO_LEN {
+        return Err(NftError::Other(format!(
+            "memo is {} bytes, at most {MAX_MEMO_LEN} are allowed",
+            memo.len()
+        )));
+    }
+    transfer(collection_id, token_id, from, to, Some(memo), None)
+}
+
+fn transfer(
+    collection_id: usize,
+    token_id: u64,
+    from: Principal,
+    to: Principal,
+    memo: Option<Vec<u8>>,
+    idempotency_key: Option<[u8; 32]>,
+) -> Result<u128, NftError> {
+    reject_reserved_principal(to)?;
+    if to == from {
+        return Err(self_transfer());
+    }
+    let caller = runtime::caller();
+    let (txid, handoff) = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if let Some(txid) = state.replay(caller, idempotency_key, TxOp::Transfer)? {
+            // the recipient and hooks were notified by the original call
+            return Ok((txid, None));
+        }
+        let collection = state
+            .collections
+            .get_mut(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        let nft = NFTS
+            .with(|nfts| nfts.borrow().get(&nft_key(collection_id, token_id)))
+            .ok_or(NftError::InvalidToken)?;
+        collection.check_transfer(&nft, caller, from)?;
+        collection.check_recipient(&to)?;
+        let (txid, handoff) = do_transfer(&mut state, collection_id, nft, to, memo);
+        state.remember(caller, idempotency_key, TxOp::Transfer, txid);
+        Ok((txid, Some(handoff)))
+    })?;
+    announce_transfers(handoff);
+    Ok(txid)
+}
+
+/// An ownership change that is committed but not yet announced to the
+/// collection's transfer hooks and the recipient.
+struct Handoff {
+    collection_id: usize,
+    token_id: u64,
+    from: Principal,
+    to: Principal,
+    hooks: Vec<TransferHook>,
+    notify_recipient: bool,
+}
+
+/// Sends a canister recipient a one-way `on_nft_received(collection_id,
+/// token_id, from)` call and each hook a one-way `(collection_id, token_id,
+/// from, to)` call. The transfers are committed by then: a recipient without
+/// `on_nft_received` cannot undo them, and a hook that cannot be reached only
+/// leaves a `TransferHookFailed` event behind. Must run outside any `STATE`
+/// borrow.
+fn announce_transfers(handoffs: impl IntoIterator<Item = Handoff>) {
+    for handoff in handoffs {
+        if handoff.notify_recipient {
+            let _ = runtime::notify(
+                handoff.to,
+                "on_nft_received",
+                (handoff.collection_id, handoff.token_id, handoff.from),
+            );
+        }
+        for hook in handoff.hooks {
+            let sent = runtime::notify(
+                hook.canister,
+                &hook.method,
+                (
+                    handoff.collection_id,
+                    handoff.token_id,
+                    handoff.from,
+                    handoff.to,
+                ),
+            );
+            if let Err(code) = sent {
+                STATE.with(|state| {
+                    state.borrow_mut().emit(EventKind::TransferHookFailed {
+                        collection_id: handoff.collection_id,
+                        token_id: handoff.token_id,
+                        hook,
+                        error: format!("{code:?}"),
+                    })
+                });
+            }
+        }
+    }
+}
+
+/// Whether the caller could transfer the token out of `from` right now,
+/// decided by the same checks `transfer_from_to` runs.
+#[query]
+fn can_transfer(collection_id: usize, token_id: u64, from: Principal) -> bool {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let Some(collection) = state.collections.get(&collection_id) else {
+            return false;
+        };
+        NFTS.with(|nfts| nfts.borrow().get(&nft_key(collection_id, token_id)))
+            .is_some_and(|nft| {
+                collection
+                    .check_transfer(&nft, runtime::caller(), from)
+                    .is_ok()
+            })
+    })
+}
+
+/// Moves `nft` to `to` and records the transfer. Callers must have run
+/// `check_transfer` first, and announce the returned handoff.
+fn do_transfer(
+    state: &mut State,
+    collection_id: usize,
+    nft: Nft,
+    to: Principal,
+    memo: Option<Vec<u8>>,
+) -> (u128, Handoff) {
+    let handoff = move_token(state, collection_id, nft, to);
+    let (token_id, from) = (handoff.token_id, handoff.from);
+    let fee = state
+        .collections
+        .get(&collection_id)
+        .map(|collection| collection.transfer_fee)
+        .filter(|&fee| fee > 0);
+    let txid = state.log(Transaction {
+        id: 0,
+        op: TxOp::Transfer,
+        collection_id,
+        token_id: Some(token_id),
+        from: Some(from),
+        to: Some(to),
+        memo,
+        reason: None,
+        fee,
+        caller: None,
+        timestamp: 0,
+    });
+    (txid, handoff)
+}
+
+/// The one place ownership changes hands: clears the token's approval, any
+/// pending offer and the previous owner's note, moves it in the owner index
+/// and extends its ownership history. Recording the transaction and
+/// announcing the returned handoff are up to the caller.
+fn move_token(state: &mut State, collection_id: usize, mut nft: Nft, to: Principal) -> Handoff {
+    let (token_id, from) = (nft.id, nft.owner);
+    nft.approved = None;
+    nft.approval_txid = None;
+    nft.offered_to = None;
+    state.notes.remove(&nft_key(collection_id, token_id));
+    nft.owner = to;
+    nft.owner_history.push((to, runtime::time()));
+    NFTS.with(|nfts| {
+        nfts.borrow_mut()
+            .insert(nft_key(collection_id, token_id), nft)
+    });
+    let mut hooks = Vec::new();
+    let mut notify_recipient = false;
+    if let Some(collection) = state.collections.get_mut(&collection_id) {
+        collection.reindex(token_id, Some(from), Some(to));
+        hooks.clone_from(&collection.transfer_hooks);
+        // tokens may be sent to this canister itself, but calling back into
+        // ourselves for the notification is pointless
+        notify_recipient =
+            !collection.notifications_disabled && is_canister(&to) && to != runtime::id();
+    }
+    Handoff {
+        collection_id,
+        token_id,
+        from,
+        to,
+        hooks,
+        notify_recipient,
+    }
+}
+
+/// First half of a pull transfer: the token stays with its owner until `to`
+/// calls `accept_transfer`, so it cannot end up with a principal that never
+/// claims it. A new offer replaces the previous one.
+#[update]
+fn offer_transfer(collection_id: usize, token_id: u64, to: Principal) -> Result<u128, NftError> {
+    reject_reserved_principal(to)?;
+    let caller = runtime::caller();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let collection = state
+            .collections
+            .get(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        let key = nft_key(collection_id, token_id);
+        let mut nft = NFTS
+            .with(|nfts| nfts.borrow().get(&key))
+            .ok_or(NftError::InvalidToken)?;
+        collection.check_transfer(&nft, caller, nft.owner)?;
+        collection.check_recipient(&to)?;
+        if nft.owner == to {
+            return Err(self_transfer());
+        }
+        let owner = nft.owner;
+        nft.offered_to = Some(to);
+        NFTS.with(|nfts| nfts.borrow_mut().insert(key, nft));
+        Ok(state.record(
+            TxOp::Offer,
+            collection_id,
+            Some(token_id),
+            Some(owner),
+            Some(to),
+        ))
+    })
+}
+
+/// Completes a pull transfer offered to the caller. The token is moved, and
+/// the transfer fee charged, as for `transfer_from_to`.
+#[update]
+fn accept_transfer(collection_id: usize, token_id: u64) -> Result<u128, NftError> {
+    let caller = runtime::caller();
+    let (txid, handoff) = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let collection = state
+            .collections
+            .get(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        let nft = NFTS
+            .with(|nfts| nfts.borrow().get(&nft_key(collection_id, token_id)))
+            .ok_or(NftError::InvalidToken)?;
+        if nft.offered_to != Some(caller) {
+            return Err(NftError::Unauthorized);
+        }
+        collection.check_paused()?;
+        collection.check_transferable()?;
+        collection.check_recipient(&caller)?;
+        if nft.is_locked() {
+            return Err(NftError::Locked);
+        }
+        Ok(do_transfer(&mut state, collection_id, nft, caller, None))
+    })?;
+    announce_transfers([handoff]);
+    Ok(txid)
+}
+
+#[update]
+fn cancel_offer(collection_id: usize, token_id: u64) -> Result<(), NftError> {
+    let key = nft_key(collection_id, token_id);
+    let mut nft = NFTS
+        .with(|nfts| nfts.borrow().get(&key))
+        .ok_or(NftError::InvalidToken)?;
+    if nft.owner != runtime::caller() {
+        Err(NftError::Unauthorized)
+    } else if nft.offered_to.is_none() {
+        Err(NftError::Other("token has no pending offer".to_owned()))
+    } else {
+        nft.offered_to = None;
+        NFTS.with(|nfts| nfts.borrow_mut().insert(key, nft));
+        Ok(())
+    }
+}
+
+/// Tokens of the collection waiting for `to` to accept them.
+#[query]
+fn pending_offers(collection_id: usize, to: Principal) -> Vec<u64> {
+    NFTS.with(|nfts| {
+        nfts_of_collection(&nfts.borrow(), collection_id)
+            .filter(|nft| nft.offered_to == Some(to))
+            .map(|nft| nft.id)
+            .collect()
+    })
+}
+
+/// Governance override for recovering tokens: custodians move a token without
+/// the owner's consent, recorded as an `AdminTransfer` carrying `reason`.
+/// Locked tokens stay put unless `force` is set.
+#[update]
+fn admin_transfer(
+    collection_id: usize,
+    token_id: u64,
+    to: Principal,
+    reason: String,
+    force: bool,
+) -> Result<u128, NftError> {
+    reject_reserved_principal(to)?;
+    if reason.len() > MAX_REASON_LEN {
+        return Err(NftError::Other(format!(
+            "reason cannot exceed {MAX_REASON_LEN} bytes"
+        )));
+    }
+    let (txid, handoff) = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let collection = state
+            .collections
+            .get(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        if !collection.custodians.contains(&runtime::caller()) {
+            return Err(NftError::Unauthorized);
+        }
+        collection.check_lone_custodian()?;
+        collection.check_recipient(&to)?;
+        let nft = NFTS
+            .with(|nfts| nfts.borrow().get(&nft_key(collection_id, token_id)))
+            .ok_or(NftError::InvalidToken)?;
+        if nft.is_locked() && !force {
+            return Err(NftError::Locked);
+        } else if nft.owner == to {
+            return Err(self_transfer());
+        }
+        let handoff = move_token(&mut state, collection_id, nft, to);
+        let txid = state.log(Transaction {
+            id: 0,
+            op: TxOp::AdminTransfer,
+            collection_id,
+            token_id: Some(handoff.token_id),
+            from: Some(handoff.from),
+            to: Some(to),
+            memo: None,
+            reason: Some(reason),
+            fee: None,
+            caller: None,
+            timestamp: 0,
+        });
+        Ok((txid, handoff))
+    })?;
+    announce_transfers([handoff]);
+    Ok(txid)
+}
+
+/// Returns whether the new requirement is in effect. Raising it takes one
+/// custodian, but lowering it takes as many custodians as the current
+/// requirement, each calling with the same `required`; until then the call
+/// only adds the caller's vote.
+#[update]
+fn set_transfer_approvals_required(collection_id: usize, required: u8) -> Result<bool, NftError> {
+    let caller = runtime::caller();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let collection = state
+            .collections
+            .get_mut(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        if !collection.custodians.contains(&caller) {
+            return Err(NftError::Unauthorized);
+        } else if required as usize > collection.custodians.len() {
+            return Err(NftError::Other(format!(
+                "the collection has only {} custodians",
+                collection.custodians.len()
+            )));
+        }
+        if required < collection.transfer_approvals_required.max(1)
+            && !collection.vote_custodian_change(caller, CustodianChange::LowerApprovals(required))
+        {
+            return Ok(false);
+        }
+        // votes cast under the old requirement start over
+        collection.pending_custodian_change = None;
+        collection.transfer_approvals_required = required;
+        Ok(true)
+    })
+}
+
+/// Starts a custodian transfer of a token from its current owner. It
+/// executes once enough custodians have called `approve_transfer`, or right
+/// away when the collection needs no more than one approval.
+#[update]
+fn propose_transfer(collection_id: usize, token_id: u64, to: Principal) -> Result<u64, NftError> {
+    reject_reserved_principal(to)?;
+    let caller = runtime::caller();
+    let now = runtime::time();
+    let id = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state
+            .pending_transfers
+            .retain(|_, pending| now < pending.expires_at);
+        let collection = state
+            .collections
+            .get(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        if !collection.custodians.contains(&caller) {
+            return Err(NftError::Unauthorized);
+        }
+        collection.check_recipient(&to)?;
+        let nft = NFTS
+            .with(|nfts| nfts.borrow().get(&nft_key(collection_id, token_id)))
+            .ok_or(NftError::InvalidToken)?;
+        if nft.owner == to {
+            return Err(self_transfer());
+        }
+        let id = state.next_pending_transfer_id();
+        state.pending_transfers.insert(
+            id,
+            PendingTransfer {
+                id,
+                collection_id,
+                token_id,
+                from: nft.owner,
+                to,
+                approvals: HashSet::new(),
+                expires_at: now.saturating_add(PENDING_TRANSFER_TTL_NANOS),
+            },
+        );
+        Ok(id)
+    })?;
+    match approve_transfer(id) {
+        Ok(_) => Ok(id),
+        Err(err) => {
+            STATE.with(|state| state.borrow_mut().pending_transfers.remove(&id));
+            Err(err)
+        }
+    }
+}
+
+/// Adds the caller's approval and returns the txid once the transfer has
+/// executed. Only approvals of current custodians are counted. A transfer
+/// that can no longer execute because the token changed hands is dropped.
+#[update]
+fn approve_transfer(pending_id: u64) -> Result<Option<u128>, NftError> {
+    let caller = runtime::caller();
+    let now = runtime::time();
+    let executed = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state
+            .pending_transfers
+            .retain(|_, pending| now < pending.expires_at);
+        let pending = state
+            .pending_transfers
+            .get(&pending_id)
+            .ok_or_else(|| NftError::Other("invalid pending transfer id".to_owned()))?;
+        let collection = state
+            .collections
+            .get(&pending.collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        if !collection.custodians.contains(&caller) {
+            return Err(NftError::Unauthorized);
+        }
+        let approvals = pending
+            .approvals
+            .iter()
+            .chain([&caller])
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .filter(|principal| collection.custodians.contains(principal))
+            .count();
+        if approvals < collection.transfer_approvals_required.max(1) as usize {
+            if let Some(pending) = state.pending_transfers.get_mut(&pending_id) {
+                pending.approvals.insert(caller);
+            }
+            return Ok(None);
+        }
+        collection.check_paused()?;
+        collection.check_transferable()?;
+        collection.check_recipient(&pending.to)?;
+        let (collection_id, to) = (pending.collection_id, pending.to);
+        let nft = NFTS.with(|nfts| nfts.borrow().get(&nft_key(collection_id, pending.token_id)));
+        let nft = match nft {
+            Some(nft) if nft.owner == pending.from => nft,
+            _ => {
+                state.pending_transfers.remove(&pending_id);
+                return Err(NftError::Other(
+                    "the token has changed hands since the transfer was proposed".to_owned(),
+                ));
+            }
+        };
+        if nft.is_locked() {
+            return Err(NftError::Locked);
+        }
+        state.pending_transfers.remove(&pending_id);
+        Ok(Some(do_transfer(&mut state, collection_id, nft, to, None)))
+    })?;
+    Ok(executed.map(|(txid, handoff)| {
+        announce_transfers([handoff]);
+        txid
+    }))
+}
+
+#[query]
+fn pending_transfers(collection_id: usize) -> Vec<PendingTransfer> {
+    let now = runtime::time();
+    STATE.with(|state| {
+        let mut pending: Vec<_> = state
+            .borrow()
+            .pending_transfers
+            .values()
+            .filter(|pending| pending.collection_id == collection_id && now < pending.expires_at)
+            .cloned()
+            .collect();
+        pending.sort_by_key(|pending| pending.id);
+        pending
+    })
+}
+
+/// Rejects principals that can never act on a token they receive: the
+/// anonymous principal and the management canister. The canister's own id is
+/// allowed, since tokens may be parked with the canister itself.
+fn reject_reserved_principal(principal: Principal) -> Result<(), NftError> {
+    if principal == ANONYMOUS {
+        Err(NftError::ZeroAddress)
+    } else if principal == Principal::management_canister() {
+        Err(NftError::Other(
+            "the management canister cannot hold tokens".to_owned(),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+fn self_transfer() -> NftError {
+    NftError::Other("token is already owned by the recipient".to_owned())
+}
+
+fn is_canister(principal: &Principal) -> bool {
+    // canister ids are opaque principals, tagged with a trailing 0x01 byte
+    principal.as_slice().last() == Some(&0x01)
+}
+
+#[update]
+fn register_transfer_hook(
+    collection_id: usize,
+    canister: Principal,
+    method: String,
+) -> Result<(), NftError> {
+    validate_label("method", &method, MAX_METHOD_NAME_LEN)?;
+    if !is_canister(&canister) {
+        return Err(NftError::Other(
+            "transfer hooks must be canisters".to_owned(),
+        ));
+    }
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let collection = state
+            .collections
+            .get_mut(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        let hook = TransferHook { canister, method };
+        if !collection.custodians.contains(&runtime::caller()) {
+            Err(NftError::Unauthorized)
+        } else if collection.transfer_hooks.contains(&hook) {
+            Ok(())
+        } else if collection.transfer_hooks.len() >= MAX_TRANSFER_HOOKS {
+            Err(NftError::Other(format!(
+                "cannot register more than {MAX_TRANSFER_HOOKS} transfer hooks"
+            )))
+        } else {
+            collection.transfer_hooks.push(hook);
+            Ok(())
+        }
+    })
+}
+
+#[update]
+fn unregister_transfer_hook(
+    collection_id: usize,
+    canister: Principal,
+    method: String,
+) -> Result<bool, NftError> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let collection = state
+            .collections
+            .get_mut(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        if collection.custodians.contains(&runtime::caller()) {
+            let hook = TransferHook { canister, method };
+            let len = collection.transfer_hooks.len();
+            collection.transfer_hooks.retain(|other| *other != hook);
+            Ok(collection.transfer_hooks.len() < len)
+        } else {
+            Err(NftError::Unauthorized)
+        }
+    })
+}
+
+#[query]
+fn transfer_hooks(collection_id: usize) -> Vec<TransferHook> {
+    STATE.with(|state| {
+        state
+            .borrow()
+            .collections
+            .get(&collection_id)
+            .map(|collection| collection.transfer_hooks.clone())
+            .unwrap_or_default()
+    })
+}
+
+#[update]
+fn set_transfer_notifications(collection_id: usize, enabled: bool) -> Result<(), NftError> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let collection = state
+            .collections
+            .get_mut(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        if collection.custodians.contains(&runtime::caller()) {
+            collection.notifications_disabled = !enabled;
+            Ok(())
+        } else {
+            Err(NftError::Unauthorized)
+        }
+    })
+}
+
+#[update]
+fn set_recipient_denied(
+    collection_id: usize,
+    recipient: Principal,
+    denied: bool,
+) -> Result<(), NftError> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let collection = state
+            .collections
+            .get_mut(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        if !collection.custodians.contains(&runtime::caller()) {
+            Err(NftError::Unauthorized)
+        } else {
+            if denied {
+                collection.denied_recipients.insert(recipient);
+            } else {
+                collection.denied_recipients.remove(&recipient);
+            }
+            Ok(())
+        }
+    })
+}
+
+#[query]
+fn denied_recipients(collection_id: usize) -> Vec<Principal> {
+    STATE.with(|state| {
+        let mut denied: Vec<_> = state
+            .borrow()
+            .collections
+            .get(&collection_id)
+            .map(|collection| collection.denied_recipients.iter().copied().collect())
+            .unwrap_or_default();
+        denied.sort();
+        denied
+    })
+}
+
+#[update]
+fn batch_transfer(
+    collection_id: usize,
+    token_ids: Vec<u64>,
+    from: Principal,
+    to: Principal,
+) -> Result<Vec<u128>, NftError> {
+    if let Err(err) = reject_reserved_principal(to) {
+        Err(err)
+    } else if to == from {
+        Err(self_transfer())
+    } else {
+        let caller = runtime::caller();
+        let moved: Vec<_> = STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            let collection = state
+                .collections
+                .get_mut(&collection_id)
+                .ok_or(NftError::InvalidCollection)?;
+            collection.check_recipient(&to)?;
+            let mut seen = HashSet::new();
+            let mut nfts = Vec::with_capacity(token_ids.len());
+            for token_id in token_ids {
+                if !seen.insert(token_id) {
+                    return Err(NftError::Other(format!("duplicate token id {token_id}")));
+                }
+                let nft = NFTS
+                    .with(|nfts| nfts.borrow().get(&nft_key(collection_id, token_id)))
+                    .ok_or(NftError::InvalidToken)?;
+                collection.check_transfer(&nft, caller, from)?;
+                nfts.push(nft);
+            }
+            Ok(nfts
+                .into_iter()
+                .map(|nft| do_transfer(&mut state, collection_id, nft, to, None))
+                .collect())
+        })?;
+        let (txids, handoffs): (Vec<_>, Vec<_>) = moved.into_iter().unzip();
+        announce_transfers(handoffs);
+        Ok(txids)
+    }
+}
+
+/// Deprecated: the union over all collections, kept for existing clients.
+/// Use `supported_interfaces_of` instead.
+#[query]
+fn supported_interfaces() -> Vec<Interface> {
+    vec![
+        Interface::Approval,
+        Interface::TransferNotification,
+        Interface::Burn,
+        Interface::Mint,
+    ]
+}
+
+#[query]
+fn supported_interfaces_of(collection_id: usize) -> Vec<Interface> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let Some(collection) = state.collections.get(&collection_id) else {
+            return vec![];
+        };
+        let mut interfaces = vec![Interface::Approval];
+        if !collection.notifications_disabled {
+            interfaces.push(Interface::TransferNotification);
+        }
+        interfaces.push(Interface::Burn);
+        if collection.check_supply(1).is_ok() {
+            interfaces.push(Interface::Mint);
+        }
+        interfaces
+    })
+}
+
+#[query]
+fn total_supply() -> usize {
+    NFTS.with(|nfts| nfts.borrow().len() as usize)
+}
+
+#[derive(CandidType)]
+pub struct Stats {
+    num_collections: usize,
+    total_nfts: usize,
+    total_burned: usize,
+    total_transactions: u128,
+    unique_owners: usize,
+}
+
+#[query]
+fn stats() -> Stats {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let collections = state.collections.values();
+        Stats {
+            num_collections: state.collections.len(),
+            total_nfts: total_supply(),
+            total_burned: collections
+                .clone()
+                .map(|collection| collection.burned.len())
+                .sum(),
+            total_transactions: state.txid,
+            unique_owners: collections
+                .flat_map(|collection| collection.owner_index.keys())
+                .collect::<HashSet<_>>()
+                .len(),
+        }
+    })
+}
+
+#[query]
+fn version() -> String {
+    env!("CARGO_PKG_VERSION").to_owned()
+}
+
+#[derive(CandidType)]
+pub struct HealthStatus {
+    /// The cycles balance meets every collection's minting threshold.
+    ok: bool,
+    num_collections: usize,
+    txid: u128,
+    uptime_since_last_upgrade_ns: u64,
+}
+
+#[query]
+fn health() -> HealthStatus {
+    STATE.with(|state| {
+        let state = state.borrow();
+        HealthStatus {
+            ok: state
+                .collections
+                .values()
+                .all(|collection| collection.check_cycles().is_ok()),
+            num_collections: state.collections.len(),
+            txid: state.txid,
+            uptime_since_last_upgrade_ns: runtime::time().saturating_sub(state.upgraded_at),
+        }
+    })
+}
+
+#[derive(CandidType)]
+pub struct MemoryStats {
+    heap_bytes: u64,
+    stable_bytes: u64,
+    num_nfts: u64,
+    total_content_bytes: u64,
+}
+
+/// Walks every stored NFT, so the cost grows with the canister.
+#[query]
+fn memory_stats() -> MemoryStats {
+    let (num_nfts, total_content_bytes) = NFTS.with(|nfts| {
+        nfts.borrow()
+            .iter()
+            .fold((0, 0), |(count, bytes), (_, nft)| {
+                (count + 1, bytes + nft.content.len() as u64)
+            })
+    });
+    MemoryStats {
+        heap_bytes: runtime::heap_bytes(),
+        stable_bytes: runtime::stable_bytes(),
+        num_nfts,
+        total_content_bytes,
+    }
+}
+
+#[query]
+fn total_supply_of_collection(collection_id: usize) -> Option<usize> {
+    if STATE.with(|state| state.borrow().collections.contains_key(&collection_id)) {
+        NFTS.with(|nfts| Some(nfts_of_collection(&nfts.borrow(), collection_id).count()))
+    } else {
+        None
+    }
+}
+
+#[query]
+fn tokens_of_collection(collection_id: usize, offset: usize, limit: usize) -> Vec<u64> {
+    NFTS.with(|nfts| {
+        nfts_of_collection(&nfts.borrow(), collection_id)
+            .skip(offset)
+            .take(limit.min(MAX_LIMIT))
+            .map(|nft| nft.id)
+            .collect()
+    })
+}
+
+/// Pages through a collection in id order. `cursor` is the last id of the
+/// previous page, and the returned cursor is `None` once nothing follows,
+/// so ids minted or burned between calls never shift a page.
+#[query]
+fn tokens_of_collection_paged(
+    collection_id: usize,
+    cursor: Option<u64>,
+    limit: usize,
+) -> (Vec<u64>, Option<u64>) {
+    let limit = limit.clamp(1, MAX_LIMIT);
+    let Some(start) = cursor.map_or(Some(0), |cursor| cursor.checked_add(1)) else {
+        return (Vec::new(), None);
+    };
+    let mut tokens: Vec<u64> = NFTS.with(|nfts| {
+        nfts.borrow()
+            .range(nft_key(collection_id, start)..=nft_key(collection_id, u64::MAX))
+            .take(limit + 1)
+            .map(|((_, token_id), _)| token_id)
+            .collect()
+    });
+    let next = if tokens.len() > limit {
+        tokens.truncate(limit);
+        tokens.last().copied()
+    } else {
+        None
+    };
+    (tokens, next)
+}
+
+/// Tokens minted in `[start_ns, end_ns)`, ordered by mint time.
+#[query]
+fn tokens_minted_between(collection_id: usize, start_ns: u64, end_ns: u64) -> Vec<u64> {
+    let mut minted: Vec<_> = NFTS.with(|nfts| {
+        nfts_of_collection(&nfts.borrow(), collection_id)
+            .filter(|nft| (start_ns..end_ns).contains(&nft.minted_at))
+            .map(|nft| (nft.minted_at, nft.id))
+            .collect()
+    });
+    minted.sort();
+    minted.into_iter().map(|(_, token_id)| token_id).collect()
+}
+
+#[query]
+fn tokens_of_owner(
+    collection_id: usize,
+    owner: Principal,
+    offset: usize,
+    limit: usize,
+) -> Vec<u64> {
+    STATE.with(|state| {
+        state
+            .borrow()
+            .collections
+            .get(&collection_id)
+            .and_then(|collection| collection.owner_index.get(&owner))
+            .map(|tokens| {
+                let mut token_ids: Vec<u64> = tokens.iter().copied().collect();
+                token_ids.sort_unstable();
+                token_ids
+                    .into_iter()
+                    .skip(offset)
+                    .take(limit.min(MAX_LIMIT))
+                    .collect()
+            })
+            .unwrap_or_default()
+    })
+}
+
+/// Returns whether the custodian was added. While transfers need several
+/// custodian approvals, adding one needs as many custodians calling with the
+/// same `custodian`; until then the call only adds the caller's vote.
+#[update]
+fn insert_custodian_into_collection(
+    collection_id: usize,
+    custodian: Principal,
+) -> Result<bool, NftError> {
+    let caller = runtime::caller();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let collection = state
+            .collections
+            .get_mut(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        if !collection.custodians.contains(&caller) {
+            return Err(NftError::Unauthorized);
+        }
+        reject_reserved_principal(custodian)?;
+        if collection.custodians.contains(&custodian)
+            || !collection
+                .vote_custodian_change(caller, CustodianChange::InsertCustodian(custodian))
+        {
+            Ok(false)
+        } else {
+            Ok(collection.custodians.insert(custodian))
+        }
+    })
+}
+
+#[update]
+fn remove_custodian_from_collection(
+    collection_id: usize,
+    custodian: Principal,
+) -> Result<bool, NftError> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let collection = state
+            .collections
+            .get_mut(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        if !collection.custodians.contains(&runtime::caller()) {
+            Err(NftError::Unauthorized)
+        } else if !collection.custodians.contains(&custodian) {
+            Ok(false)
+        } else if collection.custodians.len() == 1 {
+            Err(NftError::Other(
+                "cannot remove the last custodian".to_owned(),
+            ))
+        } else if collection.custodians.len() - 1 < collection.transfer_approvals_required as usize
+        {
+            // the remaining custodians could never approve a transfer
+            Err(NftError::Other(format!(
+                "transfers need {} custodian approvals",
+                collection.transfer_approvals_required
+            )))
+        } else {
+            Ok(collection.custodians.remove(&custodian))
+        }
+    })
+}
+
+/// Replaces the whole custodian set in one step, so a handoff never leaves
+/// the collection with no custodians or a mix of old and new ones. Returns
+/// whether the set was replaced: like `insert_custodian_into_collection`, it
+/// takes as many current custodians as transfers need approvals.
+#[update]
+fn transfer_collection_ownership(
+    collection_id: usize,
+    new_custodians: Vec<Principal>,
+) -> Result<bool, NftError> {
+    let caller = runtime::caller();
+    for custodian in &new_custodians {
+        reject_reserved_principal(*custodian)?;
+    }
+    let custodians: HashSet<Principal> = new_custodians.into_iter().collect();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let collection = state
+            .collections
+            .get_mut(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        if !collection.custodians.contains(&caller) {
+            return Err(NftError::Unauthorized);
+        } else if custodians.is_empty() {
+            return Err(NftError::Other(
+                "a collection needs at least one custodian".to_owned(),
+            ));
+        } else if collection.transfer_approvals_required as usize > custodians.len() {
+            return Err(NftError::Other(format!(
+                "transfers need {} custodian approvals",
+                collection.transfer_approvals_required
+            )));
+        }
+        let sorted = |custodians: &HashSet<Principal>| {
+            let mut custodians: Vec<Principal> = custodians.iter().copied().collect();
+            custodians.sort_unstable();
+            custodians
+        };
+        let new = sorted(&custodians);
+        if !collection
+            .vote_custodian_change(caller, CustodianChange::ReplaceCustodians(new.clone()))
+        {
+            return Ok(false);
+        }
+        let old = sorted(&std::mem::replace(&mut collection.custodians, custodians));
+        state.emit(EventKind::CustodiansReplaced {
+            collection_id,
+            old,
+            new,
+        });
+        Ok(true)
+    })
+}
+
+#[update]
+fn grant_role(collection_id: usize, principal: Principal, role: Role) -> Result<bool, NftError> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let collection = state
+            .collections
+            .get_mut(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        if collection.custodians.contains(&runtime::caller()) {
+            Ok(collection.roles.entry(principal).or_default().insert(role))
+        } else {
+            Err(NftError::Unauthorized)
+        }
+    })
+}
+
+#[update]
+fn revoke_role(collection_id: usize, principal: Principal, role: Role) -> Result<bool, NftError> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let collection = state
+            .collections
+            .get_mut(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        if !collection.custodians.contains(&runtime::caller()) {
+            return Err(NftError::Unauthorized);
+        }
+        let Some(roles) = collection.roles.get_mut(&principal) else {
+            return Ok(false);
+        };
+        let revoked = roles.remove(&role);
+        if roles.is_empty() {
+            collection.roles.remove(&principal);
+        }
+        Ok(revoked)
+    })
+}
+
+/// Roles granted explicitly; custodians hold every role without being listed.
+#[query]
+fn roles_of(collection_id: usize, principal: Principal) -> Vec<Role> {
+    STATE.with(|state| {
+        state
+            .borrow()
+            .collections
+            .get(&collection_id)
+            .and_then(|collection| collection.roles.get(&principal))
+            .map(|roles| {
+                [Role::Minter, Role::Pauser, Role::MetadataEditor]
+                    .into_iter()
+                    .filter(|role| roles.contains(role))
+                    .collect()
+            })
+            .unwrap_or_default()
+    })
+}
+
+#[query]
+fn custodians_of_collection(collection_id: usize) -> Vec<Principal> {
+    STATE.with(|state| {
+        state
+            .borrow()
+            .collections
+            .get(&collection_id)
+            .map(|collection| {
+                let mut custodians: Vec<Principal> =
+                    collection.custodians.iter().copied().collect();
+                custodians.sort_unstable();
+                custodians
+            })
+            .unwrap_or_default()
+    })
+}
+
+#[query]
+fn is_custodian_of_collection(
+    collection_id: usize,
+    custodian: Principal,
+) -> Result<bool, NftError> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let collection = state
+            .collections
+            .get(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        Ok(collection.custodians.contains(&custodian))
+    })
+}
+
+#[update]
+fn approve(
+    collection_id: usize,
+    token_id: u64,
+    user: Principal,
+    expires_at: Option<u64>,
+) -> Result<u128, NftError> {
+    let caller = runtime::caller();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let collection = state
+            .collections
+            .get(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        collection.check_paused()?;
+        collection.check_transferable()?;
+        let key = nft_key(collection_id, token_id);
+        let mut nft = NFTS
+            .with(|nfts| nfts.borrow().get(&key))
+            .ok_or(NftError::InvalidToken)?;
+        let delegated = nft.owner == caller
+            || nft.approved_principal() == Some(caller)
+            || collection
+                .operators
+                .get(&nft.owner)
+                .map(|operators| operators.contains(&caller))
+                .unwrap_or(false);
+        if !delegated {
+            if !collection.custodians.contains(&caller) {
+                return Err(NftError::Unauthorized);
+            }
+            // an approval granted on custodian authority alone would let one
+            // custodian skip propose_transfer
+            collection.check_lone_custodian()?;
+        }
+        if nft.is_locked() {
+            Err(NftError::Locked)
+        } else if user == nft.owner {
+            Err(NftError::SelfApprove)
+        } else if expires_at.is_some_and(|expires_at| expires_at <= runtime::time()) {
+            Err(NftError::Other("approval expiry is in the past".to_owned()))
+        } else if nft.approved == Some((user, expires_at)) {
+            // re-approving is a no-op that reports the approval already in place
+            Ok(nft.approval_txid.unwrap_or(state.txid))
+        } else {
+            let txid = state.record(
+                TxOp::Approve,
+                collection_id,
+                Some(token_id),
+                Some(caller),
+                Some(user),
+            );
+            nft.approved = Some((user, expires_at));
+            nft.approval_txid = Some(txid);
+            NFTS.with(|nfts| nfts.borrow_mut().insert(key, nft));
+            Ok(txid)
+        }
+    })
+}
+
+/// Changes that would leave the caller's operators as they are record
+/// nothing and report the txid of the grant or revocation in place.
+#[update]
+fn set_approval_for_all(
+    collection_id: usize,
+    operator: Principal,
+    is_approved: bool,
+) -> Result<u128, NftError> {
+    let caller = runtime::caller();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let collection = state
+            .collections
+            .get_mut(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        collection.check_paused()?;
+        if is_approved {
+            // operators approved before the collection became soulbound can still be revoked
+            collection.check_transferable()?;
+            // approving anonymous is rejected, revoking it clears every operator
+            reject_reserved_principal(operator)?;
+        }
+        if operator == caller {
+            return Err(NftError::SelfApprove);
+        }
+        let operators = collection.operators.entry(caller).or_default();
+        let changed: Vec<Principal> = if operator == ANONYMOUS {
+            std::mem::take(operators).into_iter().collect()
+        } else if is_approved {
+            operators
+                .insert(operator)
+                .then_some(operator)
+                .into_iter()
+                .collect()
+        } else {
+            operators
+                .remove(&operator)
+                .then_some(operator)
+                .into_iter()
+                .collect()
+        };
+        if operators.is_empty() {
+            collection.operators.remove(&caller);
+        }
+        if changed.is_empty() {
+            let txid = collection
+                .operator_txids
+                .get(&caller)
+                .and_then(|txids| txids.get(&operator))
+                .copied();
+            return Ok(txid.unwrap_or(state.txid));
+        }
+        let txid = state.record(
+            TxOp::ApprovalForAll,
+            collection_id,
+            None,
+            Some(caller),
+            Some(operator),
+        );
+        let collection = state
+            .collections
+            .get_mut(&collection_id)
+            .expect("collection looked up above");
+        let txids = collection.operator_txids.entry(caller).or_default();
+        for operator in changed.into_iter().chain([operator]) {
+            txids.insert(operator, txid);
+        }
+        Ok(txid)
+    })
+}
+
+#[query]
+fn tokens_operated_by(collection_id: usize, operator: Principal) -> Vec<u64> {
+    let mut tokens: BTreeSet<u64> = STATE.with(|state| {
+        let state = state.borrow();
+        let Some(collection) = state.collections.get(&collection_id) else {
+            return BTreeSet::new();
+        };
+        collection
+            .operators
+            .iter()
+            .filter(|(_, operators)| operators.contains(&operator))
+            .filter_map(|(owner, _)| collection.owner_index.get(owner))
+            .flatten()
+            .copied()
+            .collect()
+    });
+    NFTS.with(|nfts| {
+        tokens.extend(
+            nfts_of_collection(&nfts.borrow(), collection_id)
+                .filter(|nft| nft.approved_principal() == Some(operator))
+                .map(|nft| nft.id),
+        )
+    });
+    tokens.into_iter().collect()
+}
+
+/// Expired approvals read as none, as do burned tokens.
+#[query]
+fn get_approved(collection_id: usize, token_id: u64) -> Option<Principal> {
+    NFTS.with(|nfts| nfts.borrow().get(&nft_key(collection_id, token_id)))
+        .and_then(|nft| nft.approved_principal())
+}
+
+#[query]
+fn is_approved_for_all(
+    collection_id: usize,
+    owner: Principal,
+    operator: Principal,
+) -> Result<bool, NftError> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let collection = state
+            .collections
+            .get(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        Ok(collection
+            .operators
+            .get(&owner)
+            .map(|s| s.contains(&operator))
+            .unwrap_or(false))
+    })
+}
+
+#[query]
+fn operators_of(collection_id: usize, owner: Principal) -> Vec<Principal> {
+    STATE.with(|state| {
+        let mut operators: Vec<_> = state
+            .borrow()
+            .collections
+            .get(&collection_id)
+            .and_then(|collection| collection.operators.get(&owner))
+            .map(|operators| operators.iter().copied().collect())
+            .unwrap_or_default();
+        operators.sort();
+        operators
+    })
+}
+
+#[update]
+fn burn(collection_id: usize, token_id: u64) -> Result<u128, NftError> {
+    let caller = runtime::caller();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let collection = state
+            .collections
+            .get_mut(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        collection.check_paused()?;
+        let key = nft_key(collection_id, token_id);
+        let nft = NFTS
+            .with(|nfts| nfts.borrow().get(&key))
+            .ok_or(NftError::InvalidToken)?;
+        let allowed = match collection.burn_policy {
+            BurnPolicy::OwnerOnly => nft.owner == caller,
+            BurnPolicy::OwnerOrCustodian => {
+                nft.owner == caller || collection.custodians.contains(&caller)
+            }
+            BurnPolicy::OwnerOrDelegate => {
+                nft.owner == caller
+                    || nft.approved_principal() == Some(caller)
+                    || collection
+                        .operators
+                        .get(&nft.owner)
+                        .is_some_and(|operators| operators.contains(&caller))
+            }
+            BurnPolicy::Disabled => false,
+        };
+        if !allowed {
+            Err(NftError::Unauthorized)
+        } else if nft.is_locked() {
+            Err(NftError::Locked)
+        } else {
+            NFTS.with(|nfts| nfts.borrow_mut().remove(&key));
+            collection.burned.insert(token_id);
+            collection.reindex(token_id, Some(nft.owner), None);
+            if collection.unique_content {
+                let hash: [u8; 32] = Sha256::digest(&nft.content).into();
+                collection.content_hashes.remove(&hash);
+            }
+            state.notes.remove(&key);
+            Ok(state.record(
+                TxOp::Burn,
+                collection_id,
+                Some(token_id),
+                Some(nft.owner),
+                None,
+            ))
+        }
+    })
+}
+
+/// Notes are only returned to the caller who owns the token, but like all
+/// canister state they are not encrypted. An empty note removes it.
+#[update]
+fn set_note(collection_id: usize, token_id: u64, note: String) -> Result<(), NftError> {
+    if note.len() > MAX_NOTE_LEN {
+        return Err(NftError::Other(format!(
+            "note cannot exceed {MAX_NOTE_LEN} bytes"
+        )));
+    }
+    let key = nft_key(collection_id, token_id);
+    let nft = NFTS
+        .with(|nfts| nfts.borrow().get(&key))
+        .ok_or(NftError::InvalidToken)?;
+    if nft.owner != runtime::caller() {
+        return Err(NftError::Unauthorized);
+    }
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if note.is_empty() {
+            state.notes.remove(&key);
+        } else {
+            state.notes.insert(key, note);
+        }
+    });
+    Ok(())
+}
+
+#[query]
+fn my_note(collection_id: usize, token_id: u64) -> Option<String> {
+    let key = nft_key(collection_id, token_id);
+    let owner = NFTS.with(|nfts| nfts.borrow().get(&key))?.owner;
+    if owner == runtime::caller() {
+        STATE.with(|state| state.borrow().notes.get(&key).cloned())
+    } else {
+        None
+    }
+}
+
+#[update]
+fn set_burn_policy(collection_id: usize, policy: BurnPolicy) -> Result<(), NftError> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let collection = state
+            .collections
+            .get_mut(&collection_id)
+            .ok_or(NftError::InvalidCollection)?;
+        if collection.custodians.contains(&runtime::caller()) {
+            collection.burn_policy = policy;
+            Ok(())
+        } else {
+            Err(NftError::Unauthorized)
+        }
+    })
+}
+
+/// Burns the token under the collection's burn policy and records a
+/// redemption for the fulfillment service to pick up.
+#[update]
+fn burn_for_redemption(
+    collection_id: usize,
+    token_id: u64,
+    shipping_hash: Vec<u8>,
+) -> Result<u128, NftError> {
+    if shipping_hash.is_empty() || shipping_hash.len() > MAX_SHIPPING_HASH_LEN {
+        return Err(NftError::Other(format!(
+            "shipping hash must be 1 to {MAX_SHIPPING_HASH_LEN} bytes"
+        )));
+    } else if is_burned(collection_id, token_id) {
+        return Err(NftError::Other("token is already burned".to_owned()));
+    }
+    let txid = burn(collection_id, token_id)?;
+    STATE.with(|state| {
+        state.borrow_mut().redemptions.push(Redemption {
+            collection_id,
+            token_id,
+            burner: runtime::caller(),
+            shipping_hash,
+            txid,
+            time: runtime::time(),
+        })
+    });
+    Ok(txid)
+}
+
+#[query]
+fn redemptions_of(principal: Principal) -> Vec<Redemption> {
+    STATE.with(|state| {
+        state
+            .borrow()
+            .redemptions
+            .iter()
+            .filter(|redemption| redemption.burner == principal)
+            .cloned()
+            .collect()
+    })
+}
+
+#[query]
+fn is_burned(collection_id: usize, token_id: u64) -> bool {
+    STATE.with(|state| {
+        state
+            .borrow()
+            .collections
+            .get(&collection_id)
+            .map(|collection| collection.burned.contains(&token_id))
+            .unwrap_or(false)
+    })
+}
+
+/// Burned tokens are removed rather than moved to a sink principal, so they
+/// show up here and in no balance or owner query.
+#[query]
+fn burned_count(collection_id: usize) -> usize {
+    STATE.with(|state| {
+        state
+            .borrow()
+            .collections
+            .get(&collection_id)
+            .map(|collection| collection.burned.len())
+            .unwrap_or_default()
+    })
+}
+
+/// Up to `limit` transactions of the token from txid `start` on, and the
+/// txid to resume from when the ledger goes on. Each call looks at no more
+/// than `MAX_TRANSACTION_SCAN` entries, so a page can come back short, or
+/// empty, with a cursor to continue.
+#[query]
+fn transactions_of_token(
+    collection_id: usize,
+    token_id: u64,
+    start: u128,
+    limit: usize,
+) -> (Vec<Transaction>, Option<u128>) {
+    scan_transactions(start, limit, |tx| {
+        tx.collection_id == collection_id && tx.token_id == Some(token_id)
+    })
+}
+
+/// Pages like `transactions_of_token`.
+#[query]
+fn transactions_of_principal(
+    principal: Principal,
+    start: u128,
+    limit: usize,
+) -> (Vec<Transaction>, Option<u128>) {
+    scan_transactions(start, limit, |tx| {
+        tx.from == Some(principal) || tx.to == Some(principal)
+    })
+}
+
+fn scan_transactions(
+    start: u128,
+    limit: usize,
+    filter: impl Fn(&Transaction) -> bool,
+) -> (Vec<Transaction>, Option<u128>) {
+    TRANSACTIONS.with(|transactions| {
+        let transactions = transactions.borrow();
+        let Some(first) = transactions.get(0) else {
+            return (Vec::new(), None);
+        };
+        let begin = u64::try_from(start.saturating_sub(first.id)).unwrap_or(u64::MAX);
+        let end = transactions
+            .len()
+            .min(begin.saturating_add(MAX_TRANSACTION_SCAN));
+        let limit = limit.min(MAX_LIMIT);
+        let mut found = Vec::new();
+        let mut index = begin;
+        while index < end && found.len() < limit {
+            if let Some(tx) = transactions.get(index).filter(|tx| filter(tx)) {
+                found.push(tx);
+            }
+            index += 1;
+        }
+        let next = (index < transactions.len()).then(|| first.id + index as u128);
+        (found, next)
+    })
+}
+
+/// Up to `limit` transactions from txid `start` on. Txids are contiguous,
+/// and 1-based on canisters that kept a ledger from the start, so paging
+/// with `start` set to the last id seen plus one neither skips nor repeats
+/// an entry. A `start` past the end returns nothing.
+#[query]
+fn get_transactions(start: u128, limit: usize) -> Vec<Transaction> {
+    TRANSACTIONS.with(|transactions| {
+        let transactions = transactions.borrow();
+        let Some(first) = transactions.get(0) else {
+            return Vec::new();
+        };
+        let index = u64::try_from(start.saturating_sub(first.id)).unwrap_or(u64::MAX);
+        (index..transactions.len())
+            .take(limit.min(MAX_LIMIT))
+            .filter_map(|index| transactions.get(index))
+            .collect()
+    })
+}
+
+#[query]
+fn current_txid() -> u128 {
+    STATE.with(|state| state.borrow().txid)
+}
+
+#[query]
+fn op_counts() -> HashMap<String, u64> {
+    STATE.with(|state| state.borrow().op_counts.clone())
+}
+
+#[query]
+fn poll_events(after_seq: u64, limit: usize) -> Vec<Event> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let start = state.events.partition_point(|event| event.seq <= after_seq);
+        state
+            .events
+            .range(start..)
+            .take(limit.min(MAX_LIMIT))
+            .cloned()
+            .collect()
+    })
+}
+
+/// Subscribing again with the same method replaces the filter. Only the
+/// subscribing canister itself or a controller may point deliveries at it.
+#[update]
+fn subscribe(canister: Principal, method: String, filter: EventFilter) -> Result<(), NftError> {
+    validate_label("method", &method, MAX_METHOD_NAME_LEN)?;
+    let caller = runtime::caller();
+    if caller != canister && !is_controller(caller) {
+        return Err(NftError::Unauthorized);
+    } else if !is_canister(&canister) {
+        return Err(NftError::Other("subscribers must be canisters".to_owned()));
+    }
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let subscribers = &mut state.subscribers;
+        match subscribers
+            .iter()
+            .position(|subscriber| subscriber.canister == canister && subscriber.method == method)
+        {
+            Some(i) => subscribers[i].filter = filter,
+            None if subscribers.len() >= MAX_SUBSCRIBERS => {
+                return Err(NftError::Other(format!(
+                    "cannot have more than {MAX_SUBSCRIBERS} subscribers"
+                )))
+            }
+            None => subscribers.push(Subscriber {
+                canister,
+                method,
+                filter,
+            }),
+        }
+        Ok(())
+    })
+}
+
+#[update]
+fn unsubscribe(canister: Principal, method: String) -> Result<bool, NftError> {
+    let caller = runtime::caller();
+    if caller != canister && !is_controller(caller) {
+        return Err(NftError::Unauthorized);
+    }
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let len = state.subscribers.len();
+        state
+            .subscribers
+            .retain(|subscriber| subscriber.canister != canister || subscriber.method != method);
+        Ok(state.subscribers.len() < len)
+    })
+}
+
+#[update]
+fn create_proposal(
+    description: String,
+    voting_period_secs: u64,
+    quorum_bps: u16,
+    threshold_bps: u16,
+) -> Result<u64, NftError> {
+    if quorum_bps > 10_000 || threshold_bps > 10_000 {
+        return Err(NftError::Other(
+            "quorum and threshold must be at most 10000 bps".to_owned(),
+        ));
+    }
+    if description.len() > MAX_DESCRIPTION_LEN {
+        return Err(NftError::Other(format!(
+            "description cannot exceed {MAX_DESCRIPTION_LEN} bytes"
+        )));
+    }
+    let caller = runtime::caller();
+    let created = runtime::time();
+    let eligible_supply = total_supply() as u128;
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        // only holders, who could vote on it, may put a proposal up
+        if caller == ANONYMOUS
+            || state
+                .collections
+                .values()
+                .all(|collection| collection.balance_of(&caller) == 0)
+        {
+            return Err(NftError::Unauthorized);
+        }
+        let id = state.next_proposal_id();
+        state.proposals.insert(
+            id,
+            Proposal {
+                id,
+                description,
+                created,
+                deadline: created.saturating_add(voting_period_secs.saturating_mul(1_000_000_000)),
+                yes: 0,
+                no: 0,
+                voters: HashSet::new(),
+                quorum_bps,
+                threshold_bps,
+                eligible_supply,
+            },
+        );
+        state.emit(EventKind::ProposalCreated(id));
+        Ok(id)
+    })
+}
+
+/// The caller votes with every token they hold that existed when the
+/// proposal was created and has not voted on it yet, one vote per token.
+#[update]
+fn vote(proposal_id: u64, approve: bool) -> Result<(), NftError> {
+    let caller = runtime::caller();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = &mut *state;
+        let proposal = state
+            .proposals
+            .get_mut(&proposal_id)
+            .ok_or_else(|| NftError::Other("invalid proposal id".to_owned()))?;
+        let eligible: Vec<(u64, u64)> = NFTS.with(|nfts| {
+            let nfts = nfts.borrow();
+            state
+                .collections
+                .iter()
+                .flat_map(|(&collection_id, collection)| {
+                    collection
+                        .owner_index
+                        .get(&caller)
+                        .into_iter()
+                        .flatten()
+                        .map(move |&token_id| nft_key(collection_id, token_id))
+                })
+                .filter(|key| {
+                    nfts.get(key)
+                        .is_some_and(|nft| nft.minted_at <= proposal.created)
+                })
+                .collect()
+        });
+        let voted = state.voted_tokens.entry(proposal_id).or_default();
+        let fresh: Vec<(u64, u64)> = eligible
+            .iter()
+            .filter(|key| !voted.contains(key))
+            .copied()
+            .collect();
+        if runtime::time() > proposal.deadline {
+            Err(NftError::Other("voting has closed".to_owned()))
+        } else if eligible.is_empty() || caller == ANONYMOUS {
+            Err(NftError::Unauthorized)
+        } else if proposal.voters.contains(&caller) {
+            Err(NftError::Other("already voted".to_owned()))
+        } else if fresh.is_empty() {
+            Err(NftError::Other(
+                "these tokens have already voted".to_owned(),
+            ))
+        } else {
+            proposal.voters.insert(caller);
+            let weight = fresh.len() as u128;
+            voted.extend(fresh);
+            if approve {
+                proposal.yes += weight;
+            } else {
+                proposal.no += weight;
+            }
+            Ok(())
+        }
+    })
+}
+
+#[query]
+fn proposal(proposal_id: u64) -> Option<Proposal> {
+    STATE.with(|state| state.borrow().proposals.get(&proposal_id).cloned())
+}
+
+#[query]
+fn proposal_result(proposal_id: u64) -> Option<ProposalResult> {
+    STATE.with(|state| {
+        state
+            .borrow()
+            .proposals
+            .get(&proposal_id)
+            .map(Proposal::result)
+    })
+}
+
+#[query]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    let path = request.url.split('?').next().unwrap_or_default();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match segments[..] {
+        ["collection", collection_id, "token", token_id] => {
+            let (Ok(collection_id), Ok(token_id)) = (collection_id.parse(), token_id.parse())
+            else {
+                return HttpResponse::not_found();
+            };
+            NFTS.with(|nfts| nfts.borrow().get(&nft_key(collection_id, token_id)))
+                .map(|nft| {
+                    let content_type = nft
+                        .metadata
+                        .iter()
+                        .find_map(|part| match part.key_val_data.get("contentType") {
+                            Some(MetadataValue::Text(content_type)) => {
+                                Some(content_type.to_owned())
+                            }
+                            _ => None,
+                        })
+                        .unwrap_or_else(|| "application/octet-stream".to_owned());
+                    HttpResponse::ok(&content_type, nft.content)
+                })
+                .unwrap_or_else(HttpResponse::not_found)
+        }
+        ["collection", collection_id, "token", token_id, "metadata"] => {
+            let (Ok(collection_id), Ok(token_id)) = (collection_id.parse(), token_id.parse())
+            else {
+                return HttpResponse::not_found();
+            };
+            token_metadata_json(collection_id, token_id)
+                .map(|json| HttpResponse::ok("application/json", json.into_bytes()))
+                .unwrap_or_else(HttpResponse::not_found)
+        }
+        ["collection", collection_id, "logo"] => {
+            let Some((logo, hash)) = collection_id.parse().ok().and_then(|collection_id| {
+                STATE.with(|state| {
+                    state
+                        .borrow()
+                        .collections
+                        .get(&collection_id)
+                        .map(|collection| {
+                            (collection.logo.to_owned(), collection.logo_hash.to_owned())
+                        })
+                })
+            }) else {
+                return HttpResponse::not_found();
+            };
+            let etag = format!("\"{hash}\"");
+            let cached = request
+                .headers
+                .iter()
+                .filter(|(name, _)| name.eq_ignore_ascii_case("if-none-match"))
+                .flat_map(|(_, value)| value.split(','))
+                .map(|tag| tag.trim().trim_start_matches("W/"))
+                .any(|tag| tag == etag || tag == "*");
+            if cached {
+                HttpResponse::not_modified(&etag)
+            } else {
+                let mut response = HttpResponse::ok(logo.extension.mime_type(), logo.data);
+                response.headers.push(("ETag".to_owned(), etag));
+                response
+            }
+        }
+        _ => HttpResponse::not_found(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insert(name: &str) -> usize {
+        insert_collection(InsertCollection {
+            name: name.to_owned(),
+            logo: Logo::default(),
+            symbol: name.to_uppercase(),
+        })
+        .unwrap()
+    }
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id; 29])
+    }
+
+    /// Inserts a collection managed by `custodian` and mints one token to `owner`.
+    fn minted(custodian: Principal, owner: Principal) -> (usize, u64) {
+        runtime::set_caller(custodian);
+        let collection_id = insert("chess");
+        let token_id = mint(collection_id, owner, vec![], vec![], None).unwrap();
+        (collection_id, token_id)
+    }
+
+    #[test]
+    fn collection_ids_are_not_reused_after_deletion() {
+        runtime::set_caller(principal(1));
+        assert_eq!(insert("first"), 1);
+        assert_eq!(insert("second"), 2);
+        assert_eq!(insert("third"), 3);
+        assert_eq!(delete_collection(2), Ok(()));
+        assert_eq!(insert("fourth"), 4);
+        assert_eq!(name_of_collection(3).as_deref(), Some("third"));
+        assert_eq!(name_of_collection(4).as_deref(), Some("fourth"));
+        assert_eq!(name_of_collection(2), None);
+    }
+
+    #[test]
+    fn owner_cannot_approve_self() {
+        let owner = principal(2);
+        let (collection_id, token_id) = minted(principal(1), owner);
+        runtime::set_caller(owner);
+        assert_eq!(
+            approve(collection_id, token_id, owner, None),
+            Err(NftError::SelfApprove)
+        );
+        assert_eq!(get_approved(collection_id, token_id), None);
+    }
+
+    #[test]
+    fn approving_the_same_principal_twice_does_not_bump_txid() {
+        let (owner, buyer) = (principal(2), principal(3));
+        let (collection_id, token_id) = minted(principal(1), owner);
+        runtime::set_caller(owner);
+        let txid = approve(collection_id, token_id, buyer, None).unwrap();
+        assert_eq!(approve(collection_id, token_id, buyer, None), Ok(txid));
+        assert_eq!(STATE.with(|state| state.borrow().txid), txid);
+        assert_eq!(get_approved(collection_id, token_id), Some(buyer));
+    }
+
+    #[test]
+    fn operator_can_approve_on_behalf_of_owner() {
+        let (owner, operator, buyer) = (principal(2), principal(3), principal(4));
+        let (collection_id, token_id) = minted(principal(1), owner);
+        runtime::set_caller(buyer);
+        assert_eq!(
+            approve(collection_id, token_id, buyer, None),
+            Err(NftError::Unauthorized)
+        );
+        runtime::set_caller(owner);
+        set_approval_for_all(collection_id, operator, true).unwrap();
+        runtime::set_caller(operator);
+        assert!(approve(collection_id, token_id, buyer, None).is_ok());
+        assert_eq!(get_approved(collection_id, token_id), Some(buyer));
+    }
+
+    #[test]
+    fn transfer_clears_the_previous_approval() {
+        let (owner, spender, buyer) = (principal(2), principal(3), principal(4));
+        let (collection_id, token_id) = minted(principal(1), owner);
+        runtime::set_caller(owner);
+        approve(collection_id, token_id, spender, None).unwrap();
+        transfer_from_to(collection_id, token_id, owner, buyer, None).unwrap();
+        assert_eq!(get_approved(collection_id, token_id), None);
+        runtime::set_caller(spender);
+        assert_eq!(
+            transfer_from_to(collection_id, token_id, buyer, spender, None),
+            Err(NftError::Unauthorized)
+        );
+    }
+
+    #[test]
+    fn txid_counter_reaches_its_maximum() {
+        let mut state = State {
+            txid: u128::MAX - 1,
+            ..State::default()
+        };
+        assert_eq!(state.next_txid(), u128::MAX);
+    }
+
+    #[test]
+    #[should_panic(expected = "txid counter overflowed")]
+    fn txid_counter_traps_instead_of_wrapping() {
+        let mut state = State {
+            txid: u128::MAX,
+            ..State::default()
+        };
+        state.next_txid();
+    }
+
+    #[test]
+    #[should_panic(expected = "collection id counter overflowed")]
+    fn collection_id_counter_traps_instead_of_wrapping() {
+        let mut state = State {
+            collection_id: usize::MAX,
+            ..State::default()
+        };
+        state.next_collection_id();
+    }
+
+    #[test]
+    #[should_panic(expected = "token id counter overflowed")]
+    fn token_id_counter_traps_instead_of_wrapping() {
+        let mut collection = Collection {
+            token_id: u64::MAX,
+            ..Collection::default()
+        };
+        collection.next_token_id();
+    }
+
+    #[test]
+    fn expired_approval_no_longer_authorizes_transfer() {
+        let (owner, spender) = (principal(2), principal(3));
+        let (collection_id, token_id) = minted(principal(1), owner);
+        runtime::set_time(100);
+        runtime::set_caller(owner);
+        approve(collection_id, token_id, spender, Some(200)).unwrap();
+        runtime::set_time(200);
+        assert_eq!(get_approved(collection_id, token_id), None);
+        runtime::set_caller(spender);
+        assert_eq!(
+            transfer_from_to(collection_id, token_id, owner, spender, None),
+            Err(NftError::Unauthorized)
+        );
+    }
+
+    #[test]
+    fn unexpired_approval_authorizes_transfer() {
+        let (owner, spender) = (principal(2), principal(3));
+        let (collection_id, token_id) = minted(principal(1), owner);
+        runtime::set_time(100);
+        runtime::set_caller(owner);
+        approve(collection_id, token_id, spender, Some(200)).unwrap();
+        runtime::set_time(199);
+        runtime::set_caller(spender);
+        assert!(transfer_from_to(collection_id, token_id, owner, spender, None).is_ok());
+        assert_eq!(owner_of_nft(collection_id, token_id), Some(spender));
+    }
+
+    #[test]
+    fn approvals_stored_without_expiry_still_decode() {
+        #[derive(Serialize)]
+        struct OldNft {
+            id: u64,
+            owner: Principal,
+            approved: Option<Principal>,
+            metadata: Vec<MetadataPart>,
+            content: Vec<u8>,
+        }
+        let bytes = serde_cbor::to_vec(&OldNft {
+            id: 1,
+            owner: principal(2),
+            approved: Some(principal(3)),
+            metadata: vec![],
+            content: vec![],
+        })
+        .unwrap();
+        let nft = Nft::from_bytes(Cow::Owned(bytes));
+        assert_eq!(nft.approved, Some((principal(3), None)));
+    }
+
+    fn with_burn_policy(
+        custodian: Principal,
+        owner: Principal,
+        policy: BurnPolicy,
+    ) -> (usize, u64) {
+        let (collection_id, token_id) = minted(custodian, owner);
+        runtime::set_caller(custodian);
+        set_burn_policy(collection_id, policy).unwrap();
+        (collection_id, token_id)
+    }
+
+    #[test]
+    fn owner_only_policy_lets_only_the_owner_burn() {
+        let (custodian, owner) = (principal(1), principal(2));
+        let (collection_id, token_id) = with_burn_policy(custodian, owner, BurnPolicy::OwnerOnly);
+        runtime::set_caller(custodian);
+        assert_eq!(burn(collection_id, token_id), Err(NftError::Unauthorized));
+        runtime::set_caller(owner);
+        assert!(burn(collection_id, token_id).is_ok());
+        assert!(is_burned(collection_id, token_id));
+    }
+
+    #[test]
+    fn owner_or_custodian_policy_lets_a_custodian_burn() {
+        let (custodian, owner) = (principal(1), principal(2));
+        let (collection_id, token_id) =
+            with_burn_policy(custodian, owner, BurnPolicy::OwnerOrCustodian);
+        runtime::set_caller(principal(3));
+        assert_eq!(burn(collection_id, token_id), Err(NftError::Unauthorized));
+        runtime::set_caller(custodian);
+        let txid = burn(collection_id, token_id).unwrap();
+        let tx = transactions_of_token(collection_id, token_id, 0, MAX_LIMIT)
+            .0
+            .into_iter()
+            .find(|tx| tx.id == txid)
+            .unwrap();
+        assert!(tx.op == TxOp::Burn);
+        assert_eq!(tx.from, Some(owner));
+        assert_eq!(tx.caller, Some(custodian));
+        assert_eq!(balance_of_user(collection_id, owner), 0);
+    }
+
+    #[test]
+    fn disabled_policy_rejects_every_burn() {
+        let (custodian, owner) = (principal(1), principal(2));
+        let (collection_id, token_id) = with_burn_policy(custodian, owner, BurnPolicy::Disabled);
+        for caller in [owner, custodian] {
+            runtime::set_caller(caller);
+            assert_eq!(burn(collection_id, token_id), Err(NftError::Unauthorized));
+        }
+        assert!(!is_burned(collection_id, token_id));
+    }
+
+    #[test]
+    fn exported_collection_imports_back_after_loss() {
+        let (custodian, owner) = (principal(1), principal(2));
+        let (collection_id, token_id) = minted(custodian, owner);
+        let export = export_collection(collection_id, true).unwrap();
+        let bytes = candid::encode_one(&export).unwrap();
+        STATE.with(|state| state.borrow_mut().collections.remove(&collection_id));
+        NFTS.with(|nfts| nfts.borrow_mut().remove(&nft_key(collection_id, token_id)));
+
+        let export: CollectionExport = candid::decode_one(&bytes).unwrap();
+        runtime::set_caller(custodian);
+        assert_eq!(
+            import_collection(export.clone()),
+            Err(NftError::Unauthorized)
+        );
+        runtime::set_controller(custodian);
+        assert_eq!(import_collection(export.clone()), Ok(collection_id));
+        assert_eq!(owner_of_nft(collection_id, token_id), Some(owner));
+        assert_eq!(balance_of_user(collection_id, owner), 1);
+        assert_eq!(is_content_authentic(collection_id, token_id), Some(true));
+        assert!(matches!(import_collection(export), Err(NftError::Other(_))));
+    }
+
+    #[test]
+    fn imports_keep_supply_accounting_consistent() {
+        let custodian = principal(1);
+        let (collection_id, token_id) = minted(custodian, principal(2));
+        reserve_tokens(collection_id, 2).unwrap();
+        release_reserved(collection_id, vec![token_id + 1]).unwrap();
+        let export = export_collection(collection_id, true).unwrap();
+        runtime::set_controller(custodian);
+        delete_collection(collection_id).unwrap();
+
+        let out_of_range = CollectionExport {
+            burned: vec![export.token_id + 1],
+            ..export.clone()
+        };
+        assert!(matches!(
+            import_collection(out_of_range),
+            Err(NftError::Other(_))
+        ));
+        let live_and_burned = CollectionExport {
+            burned: vec![token_id],
+            ..export.clone()
+        };
+        assert!(matches!(
+            import_collection(live_and_burned),
+            Err(NftError::Other(_))
+        ));
+        let over_released = CollectionExport {
+            released: export.token_id,
+            ..export.clone()
+        };
+        assert!(matches!(
+            import_collection(over_released),
+            Err(NftError::Other(_))
+        ));
+        let oversized_logo = CollectionExport {
+            logo: Logo {
+                data: vec![0; DEFAULT_MAX_LOGO_SIZE + 1],
+                ..Logo::default()
+            },
+            ..export.clone()
+        };
+        assert!(matches!(
+            import_collection(oversized_logo),
+            Err(NftError::Other(_))
+        ));
+
+        assert_eq!(import_collection(export), Ok(collection_id));
+        assert_eq!(reserved_tokens(collection_id), vec![token_id + 2]);
+        STATE.with(|state| {
+            assert_eq!(
+                state.borrow().collections[&collection_id].allocated_supply(),
+                2
+            );
+        });
+    }
+
+    #[test]
+    fn self_transfer_does_not_consume_a_txid() {
+        let owner = principal(2);
+        let (collection_id, token_id) = minted(principal(1), owner);
+        let txid = current_txid();
+        runtime::set_caller(owner);
+        assert!(matches!(
+            transfer_from_to(collection_id, token_id, owner, owner, None),
+            Err(NftError::Other(_))
+        ));
+        assert!(batch_transfer(collection_id, vec![token_id], owner, owner).is_err());
+        assert_eq!(current_txid(), txid);
+        assert_eq!(
+            transactions_of_token(collection_id, token_id, 0, MAX_LIMIT)
+                .0
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn reservations_hold_supply_until_claimed_or_released() {
+        let custodian = principal(1);
+        let claimer = principal(2);
+        let (collection_id, _) = minted(custodian, principal(3));
+        assert_eq!(set_max_supply(collection_id, 4), Ok(()));
+        let reserved = reserve_tokens(collection_id, 3).unwrap();
+        assert_eq!(reserved, vec![2, 3, 4]);
+        assert_eq!(
+            mint(collection_id, claimer, vec![], vec![], None),
+            Err(NftError::SupplyExhausted)
+        );
+        assert_eq!(total_supply_of_collection(collection_id), Some(1));
+
+        runtime::set_caller(claimer);
+        assert_eq!(
+            claim_reserved(collection_id, 2, vec![], vec![]),
+            Err(NftError::Unauthorized)
+        );
+        runtime::set_caller(custodian);
+        assert_eq!(set_claim_allowed(collection_id, claimer, true), Ok(()));
+        runtime::set_caller(claimer);
+        assert_eq!(claim_reserved(collection_id, 2, vec![], vec![]), Ok(()));
+        assert_eq!(owner_of_nft(collection_id, 2), Some(claimer));
+        assert_eq!(
+            claim_reserved(collection_id, 3, vec![], vec![]),
+            Err(NftError::Unauthorized)
+        );
+
+        runtime::set_caller(custodian);
+        assert!(release_reserved(collection_id, vec![2, 3]).is_err());
+        assert_eq!(release_reserved(collection_id, vec![3, 4]), Ok(()));
+        assert!(reserved_tokens(collection_id).is_empty());
+        let token_id = mint(collection_id, claimer, vec![], vec![], None).unwrap();
+        assert_eq!(token_id, 5);
+        assert_eq!(
+            reserve_tokens(collection_id, 2),
+            Err(NftError::SupplyExhausted)
+        );
+    }
+
+    #[test]
+    fn restore_round_trips_state() {
+        let owner = principal(2);
+        let (collection_id, token_id) = minted(principal(1), owner);
+        mint(collection_id, principal(3), vec![], vec![1, 2, 3], None).unwrap();
+        runtime::set_caller(owner);
+        approve(collection_id, token_id, principal(4), Some(10)).unwrap();
+        assert!(set_approval_for_all(collection_id, principal(5), true).is_ok());
+        transfer_from_to(collection_id, token_id, owner, principal(6), None).unwrap();
+        runtime::set_caller(principal(6));
+        create_proposal("upgrade".to_owned(), 60, 1000, 5000).unwrap();
+        vote(1, true).unwrap();
+
+        let saved = STATE.with(|state| state.borrow().clone());
+        let bytes = serde_cbor::to_vec(&saved).unwrap();
+        let restored = restore(serde_cbor::from_slice(&bytes).unwrap());
+        assert!(restored == saved);
+        assert_eq!(
+            restored.collections[&collection_id].balance_of(&principal(6)),
+            1
+        );
+    }
+
+    #[test]
+    fn legacy_state_moves_its_nfts_to_stable_memory() {
+        #[derive(Serialize)]
+        struct BaselineNft {
+            id: u64,
+            owner: Principal,
+            approved: Option<Principal>,
+            metadata: Vec<MetadataPart>,
+            content: Vec<u8>,
+        }
+        #[derive(Serialize)]
+        struct BaselineCollection {
+            name: String,
+            logo: Logo,
+            symbol: String,
+            nfts: HashMap<u64, BaselineNft>,
+            custodians: HashSet<Principal>,
+            operators: HashMap<Principal, HashSet<Principal>>,
+        }
+        #[derive(Serialize)]
+        struct BaselineState {
+            collections: HashMap<usize, BaselineCollection>,
+            txid: u128,
+        }
+        let (owner, approved) = (principal(2), principal(3));
+        let nft = |id| BaselineNft {
+            id,
+            owner,
+            approved: Some(approved),
+            metadata: vec![],
+            content: vec![1, 2, 3],
+        };
+        let baseline = BaselineState {
+            collections: HashMap::from([(
+                1,
+                BaselineCollection {
+                    name: "chess".to_owned(),
+                    logo: Logo::default(),
+                    symbol: "CHS".to_owned(),
+                    nfts: HashMap::from([(1, nft(1)), (2, nft(2))]),
+                    custodians: HashSet::from([principal(1)]),
+                    operators: HashMap::new(),
+                },
+            )]),
+            txid: 0,
+        };
+        let bytes = serde_cbor::to_vec(&baseline).unwrap();
+        let restored = restore(migrate_legacy(&bytes));
+        STATE.with(|state| *state.borrow_mut() = restored);
+
+        assert_eq!(name_of_collection(1).as_deref(), Some("chess"));
+        assert_eq!(balance_of_user(1, owner), 2);
+        assert_eq!(owner_of_nft(1, 2), Some(owner));
+        assert_eq!(get_approved(1, 1), Some(approved));
+        runtime::set_caller(principal(1));
+        assert_eq!(mint(1, owner, vec![], vec![], None), Ok(3));
+    }
+
+    #[test]
+    #[should_panic(expected = "restored state is inconsistent")]
+    fn restore_traps_on_mismatched_token_ids() {
+        let (collection_id, token_id) = minted(principal(1), principal(2));
+        NFTS.with(|nfts| {
+            let mut nfts = nfts.borrow_mut();
+            let nft = nfts.get(&nft_key(collection_id, token_id)).unwrap();
+            nfts.insert(nft_key(collection_id, token_id + 1), nft);
+        });
+        restore(STATE.with(|state| state.borrow().clone()));
+    }
+
+    #[test]
+    fn paged_tokens_resume_after_the_cursor() {
+        let owner = principal(2);
+        let (collection_id, _) = minted(principal(1), owner);
+        for _ in 0..2 {
+            mint(collection_id, owner, vec![], vec![], None).unwrap();
+        }
+        assert_eq!(
+            tokens_of_collection_paged(collection_id, None, 2),
+            (vec![1, 2], Some(2))
+        );
+        assert_eq!(
+            tokens_of_collection_paged(collection_id, Some(2), 2),
+            (vec![3], None)
+        );
+        assert_eq!(
+            tokens_of_collection_paged(collection_id, Some(u64::MAX), 2),
+            (vec![], None)
+        );
+    }
+
+    #[test]
+    fn soulbound_tokens_burn_but_do_not_move() {
+        let custodian = principal(1);
+        let owner = principal(2);
+        let (collection_id, token_id) = minted(custodian, owner);
+        assert_eq!(set_transferable(collection_id, false), Ok(()));
+        runtime::set_caller(owner);
+        assert_eq!(
+            transfer_from_to(collection_id, token_id, owner, principal(3), None),
+            Err(NftError::NonTransferable)
+        );
+        assert_eq!(
+            approve(collection_id, token_id, principal(3), None),
+            Err(NftError::NonTransferable)
+        );
+        assert_eq!(
+            set_approval_for_all(collection_id, principal(3), true),
+            Err(NftError::NonTransferable)
+        );
+        assert!(burn(collection_id, token_id).is_ok());
+        assert!(is_burned(collection_id, token_id));
+    }
+
+    #[test]
+    fn revoking_the_last_operator_drops_the_owner_entry() {
+        let owner = principal(2);
+        let (collection_id, _) = minted(principal(1), owner);
+        runtime::set_caller(owner);
+        let operators_of_owner = || {
+            STATE.with(|state| {
+                state.borrow().collections[&collection_id]
+                    .operators
+                    .contains_key(&owner)
+            })
+        };
+        set_approval_for_all(collection_id, principal(3), true).unwrap();
+        assert!(operators_of_owner());
+        set_approval_for_all(collection_id, principal(3), false).unwrap();
+        assert!(!operators_of_owner());
+        set_approval_for_all(collection_id, principal(3), true).unwrap();
+        set_approval_for_all(collection_id, ANONYMOUS, false).unwrap();
+        assert!(!operators_of_owner());
+    }
+
+    #[test]
+    fn custodian_transfers_wait_for_enough_approvals() {
+        let (first, second) = (principal(1), principal(2));
+        let owner = principal(3);
+        let (collection_id, token_id) = minted(first, owner);
+        insert_custodian_into_collection(collection_id, second).unwrap();
+        assert_eq!(set_transfer_approvals_required(collection_id, 2), Ok(true));
+        assert!(matches!(
+            transfer_from_to(collection_id, token_id, owner, principal(4), None),
+            Err(NftError::Other(_))
+        ));
+
+        let pending_id = propose_transfer(collection_id, token_id, principal(4)).unwrap();
+        assert_eq!(approve_transfer(pending_id), Ok(None));
+        assert_eq!(owner_of_nft(collection_id, token_id), Some(owner));
+        runtime::set_caller(second);
+        assert!(matches!(approve_transfer(pending_id), Ok(Some(_))));
+        assert_eq!(owner_of_nft(collection_id, token_id), Some(principal(4)));
+        assert!(pending_transfers(collection_id).is_empty());
+
+        let pending_id = propose_transfer(collection_id, token_id, owner).unwrap();
+        runtime::set_time(PENDING_TRANSFER_TTL_NANOS);
+        runtime::set_caller(first);
+        assert!(approve_transfer(pending_id).is_err());
+        assert_eq!(owner_of_nft(collection_id, token_id), Some(principal(4)));
+    }
+
+    #[test]
+    fn creators_manage_their_new_collection() {
+        runtime::set_caller(ANONYMOUS);
+        let collection = || InsertCollection {
+            name: "puzzles".to_owned(),
+            logo: Logo::default(),
+            symbol: "PZL".to_owned(),
+        };
+        assert_eq!(insert_collection(collection()), Err(NftError::ZeroAddress));
+        runtime::set_caller(principal(1));
+        let collection_id = insert_collection(collection()).unwrap();
+        assert_eq!(
+            set_name_of_collection(collection_id, "endgames".to_owned()),
+            Ok(())
+        );
+        assert_eq!(custodians_of_collection(collection_id), vec![principal(1)]);
+    }
+
+    #[test]
+    fn transactions_page_by_txid() {
+        let owner = principal(2);
+        let (collection_id, _) = minted(principal(1), owner);
+        mint(collection_id, owner, vec![], vec![], None).unwrap();
+        mint(collection_id, owner, vec![], vec![], None).unwrap();
+        let ids = |start, limit| -> Vec<u128> {
+            get_transactions(start, limit)
+                .into_iter()
+                .map(|tx| tx.id)
+                .collect()
+        };
+        assert_eq!(ids(1, 2), vec![1, 2]);
+        assert_eq!(ids(3, 2), vec![3]);
+        assert_eq!(ids(4, 2), Vec::<u128>::new());
+        assert_eq!(ids(0, usize::MAX).len(), 3);
+    }
+
+    #[test]
+    fn reserved_principals_are_rejected() {
+        let custodian = principal(1);
+        let owner = principal(2);
+        let (collection_id, token_id) = minted(custodian, owner);
+        let management = Principal::management_canister();
+        runtime::set_caller(custodian);
+        assert!(insert_custodian_into_collection(collection_id, ANONYMOUS).is_err());
+        assert!(insert_custodian_into_collection(collection_id, management).is_err());
+        runtime::set_caller(owner);
+        assert!(set_approval_for_all(collection_id, management, true).is_err());
+        assert_eq!(
+            transfer_from_to(collection_id, token_id, owner, ANONYMOUS, None),
+            Err(NftError::ZeroAddress)
+        );
+        assert!(transfer_from_to(collection_id, token_id, owner, management, None).is_err());
+        assert_eq!(owner_of_nft(collection_id, token_id), Some(owner));
+    }
+
+    #[test]
+    fn collection_ownership_hands_off_atomically() {
+        let (collection_id, _) = minted(principal(1), principal(2));
+        runtime::set_caller(principal(2));
+        assert_eq!(
+            transfer_collection_ownership(collection_id, vec![principal(2)]),
+            Err(NftError::Unauthorized)
+        );
+        runtime::set_caller(principal(1));
+        assert!(transfer_collection_ownership(collection_id, vec![]).is_err());
+        assert_eq!(
+            transfer_collection_ownership(collection_id, vec![principal(4), principal(3)]),
+            Ok(true)
+        );
+        assert_eq!(
+            custodians_of_collection(collection_id),
+            vec![principal(3), principal(4)]
+        );
+        let event = poll_events(0, usize::MAX).pop().unwrap();
+        assert!(
+            event.kind
+                == EventKind::CustodiansReplaced {
+                    collection_id,
+                    old: vec![principal(1)],
+                    new: vec![principal(3), principal(4)],
+                }
+        );
+    }
+
+    #[test]
+    fn top_holders_rank_by_balance_then_principal() {
+        let (collection_id, _) = minted(principal(1), principal(3));
+        assert!(top_holders(collection_id + 1, 10).is_empty());
+        mint(collection_id, principal(2), vec![], vec![], None).unwrap();
+        mint(collection_id, principal(4), vec![], vec![], None).unwrap();
+        mint(collection_id, principal(4), vec![], vec![], None).unwrap();
+        assert_eq!(
+            top_holders(collection_id, 10),
+            vec![(principal(4), 2), (principal(2), 1), (principal(3), 1)]
+        );
+        assert_eq!(top_holders(collection_id, 1), vec![(principal(4), 2)]);
+    }
+
+    #[test]
+    fn metadata_schema_requires_typed_keys() {
+        let (collection_id, _) = minted(principal(1), principal(2));
+        let schema = vec![("elo".to_owned(), MetadataValueKind::Nat16)];
+        assert_eq!(set_metadata_schema(collection_id, schema), Ok(()));
+        let part = |value| MetadataPart {
+            purpose: MetadataPurpose::Rendered,
+            key_val_data: HashMap::from([("elo".to_owned(), value)]),
+            data: vec![],
+        };
+        assert!(mint(collection_id, principal(2), vec![], vec![], None).is_err());
+        assert!(mint(
+            collection_id,
+            principal(2),
+            vec![part(MetadataValue::Nat(1500))],
+            vec![],
+            None
+        )
+        .is_err());
+        let token_id = mint(
+            collection_id,
+            principal(2),
+            vec![part(MetadataValue::Nat16(1500))],
+            vec![],
+            None,
+        )
+        .unwrap();
+        assert!(set_metadata_value(
+            collection_id,
+            token_id,
+            MetadataPurpose::Rendered,
+            "elo".to_owned(),
+            MetadataValue::Text("high".to_owned())
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn idempotency_keys_replay_the_first_result() {
+        let owner = principal(2);
+        let (collection_id, _) = minted(principal(1), owner);
+        let key = Some([7; 32]);
+        let token_id = mint(collection_id, owner, vec![], vec![], key).unwrap();
+        assert_eq!(
+            mint(collection_id, owner, vec![], vec![], key),
+            Ok(token_id)
+        );
+        assert_eq!(balance_of_user(collection_id, owner), 2);
+        runtime::set_caller(owner);
+        let txid = transfer_from_to(collection_id, token_id, owner, principal(3), key).unwrap();
+        assert_eq!(
+            transfer_from_to(collection_id, token_id, owner, principal(3), key),
+            Ok(txid)
+        );
+        assert_eq!(current_txid(), txid);
+        runtime::set_time(IDEMPOTENCY_TTL_NANOS);
+        assert!(transfer_from_to(collection_id, token_id, owner, principal(3), key).is_err());
+    }
+
+    #[test]
+    fn transfer_hooks_register_once() {
+        let (collection_id, _) = minted(principal(1), principal(2));
+        let indexer = Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 9, 1, 1]);
+        let register = || register_transfer_hook(collection_id, indexer, "on_transfer".to_owned());
+        assert_eq!(register(), Ok(()));
+        assert_eq!(register(), Ok(()));
+        assert_eq!(transfer_hooks(collection_id).len(), 1);
+        assert!(
+            register_transfer_hook(collection_id, principal(2), "on_transfer".to_owned()).is_err()
+        );
+        runtime::set_caller(principal(2));
+        assert_eq!(register(), Err(NftError::Unauthorized));
+        runtime::set_caller(principal(1));
+        assert_eq!(
+            unregister_transfer_hook(collection_id, indexer, "on_transfer".to_owned()),
+            Ok(true)
+        );
+        assert!(transfer_hooks(collection_id).is_empty());
+    }
+
+    #[test]
+    fn content_over_the_limit_is_rejected() {
+        let (collection_id, token_id) = minted(principal(1), principal(2));
+        assert_eq!(
+            max_content_bytes(collection_id),
+            Some(DEFAULT_MAX_CONTENT_BYTES)
+        );
+        assert_eq!(set_max_content_bytes(collection_id, Some(4)), Ok(()));
+        let too_large = || NftError::Other("content too large".to_owned());
+        assert_eq!(
+            mint(collection_id, principal(2), vec![], vec![0; 5], None),
+            Err(too_large())
+        );
+        assert!(mint(collection_id, principal(2), vec![], vec![0; 4], None).is_ok());
+        let part = MetadataPart {
+            purpose: MetadataPurpose::Rendered,
+            key_val_data: HashMap::new(),
+            data: vec![0; 5],
+        };
+        assert_eq!(
+            replace_metadata(collection_id, token_id, vec![part]),
+            Err(too_large())
+        );
+    }
+
+    #[test]
+    fn pull_transfers_move_only_on_acceptance() {
+        let owner = principal(2);
+        let recipient = principal(3);
+        let (collection_id, token_id) = minted(principal(1), owner);
+        runtime::set_caller(owner);
+        assert!(offer_transfer(collection_id, token_id, recipient).is_ok());
+        assert_eq!(owner_of_nft(collection_id, token_id), Some(owner));
+        assert_eq!(pending_offers(collection_id, recipient), vec![token_id]);
+        assert_eq!(
+            accept_transfer(collection_id, token_id),
+            Err(NftError::Unauthorized)
+        );
+        assert_eq!(cancel_offer(collection_id, token_id), Ok(()));
+        runtime::set_caller(recipient);
+        assert_eq!(
+            accept_transfer(collection_id, token_id),
+            Err(NftError::Unauthorized)
+        );
+        runtime::set_caller(owner);
+        offer_transfer(collection_id, token_id, recipient).unwrap();
+        runtime::set_caller(recipient);
+        assert!(accept_transfer(collection_id, token_id).is_ok());
+        assert_eq!(owner_of_nft(collection_id, token_id), Some(recipient));
+        assert!(pending_offers(collection_id, recipient).is_empty());
+    }
+
+    #[test]
+    fn rarer_traits_score_higher() {
+        let (collection_id, _) = minted(principal(1), principal(2));
+        let piece = |name: &str| {
+            vec![MetadataPart {
+                purpose: MetadataPurpose::Rendered,
+                key_val_data: HashMap::from([(
+                    "piece".to_owned(),
+                    MetadataValue::Text(name.to_owned()),
+                )]),
+                data: vec![],
+            }]
+        };
+        let pawn = mint(collection_id, principal(2), piece("pawn"), vec![], None).unwrap();
+        let other_pawn = mint(collection_id, principal(2), piece("pawn"), vec![], None).unwrap();
+        let king = mint(collection_id, principal(2), piece("king"), vec![], None).unwrap();
+        let scores = rarity_scores(collection_id, 0, MAX_LIMIT);
+        assert_eq!(scores.len(), 4);
+        assert_eq!(rarity_scores(collection_id, 1, 2), scores[1..3]);
+        assert_eq!(scores[0], (king, 4 * RARITY_SCALE));
+        assert_eq!(scores[1], (pawn, 2 * RARITY_SCALE));
+        assert_eq!(scores[2], (other_pawn, 2 * RARITY_SCALE));
+        assert_eq!(scores[3].1, 0);
+    }
+
+    #[test]
+    fn minting_outside_the_window_is_rejected() {
+        let custodian = principal(1);
+        let minter = principal(5);
+        let (collection_id, _) = minted(custodian, principal(2));
+        assert_eq!(grant_role(collection_id, minter, Role::Minter), Ok(true));
+        assert_eq!(set_mint_window(collection_id, Some(10), Some(20)), Ok(()));
+        runtime::set_time(5);
+        assert!(mint(collection_id, principal(2), vec![], vec![], None).is_ok());
+        runtime::set_caller(minter);
+        assert_eq!(
+            mint(collection_id, principal(2), vec![], vec![], None),
+            Err(NftError::Other("mint not started".to_owned()))
+        );
+        runtime::set_time(10);
+        assert!(mint(collection_id, principal(2), vec![], vec![], None).is_ok());
+        runtime::set_time(20);
+        assert_eq!(
+            mint(collection_id, principal(2), vec![], vec![], None),
+            Err(NftError::Other("mint ended".to_owned()))
+        );
+    }
+
+    #[test]
+    fn queries_on_unknown_collections_do_not_trap() {
+        assert_eq!(
+            is_custodian_of_collection(42, principal(1)),
+            Err(NftError::InvalidCollection)
+        );
+        assert_eq!(
+            is_approved_for_all(42, principal(1), principal(2)),
+            Err(NftError::InvalidCollection)
+        );
+        assert!(custodians_of_collection(42).is_empty());
+        assert_eq!(balance_of_user(42, principal(1)), 0);
+        assert_eq!(owner_of_nft(42, 1), None);
+        assert!(transfer_hooks(42).is_empty());
+        assert_eq!(max_content_bytes(42), None);
+    }
+
+    #[test]
+    fn subscribers_receive_matching_events() {
+        let (collection_id, token_id) = minted(principal(1), principal(2));
+        let indexer = Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 9, 1, 1]);
+        let filter = EventFilter {
+            collection_id: Some(collection_id),
+            ops: vec![TxOp::Mint],
+        };
+        assert_eq!(
+            subscribe(indexer, "on_event".to_owned(), filter.clone()),
+            Err(NftError::Unauthorized)
+        );
+        runtime::set_caller(indexer);
+        assert_eq!(subscribe(indexer, "on_event".to_owned(), filter), Ok(()));
+        runtime::set_caller(principal(1));
+        mint(collection_id, principal(2), vec![], vec![], None).unwrap();
+        runtime::set_caller(principal(2));
+        burn(collection_id, token_id).unwrap();
+        assert_eq!(
+            runtime::take_notified(),
+            vec![(indexer, "on_event".to_owned())]
+        );
+        runtime::set_caller(indexer);
+        assert_eq!(unsubscribe(indexer, "on_event".to_owned()), Ok(true));
+    }
+
+    #[test]
+    fn notes_stay_with_their_owner() {
+        let owner = principal(2);
+        let (collection_id, token_id) = minted(principal(1), owner);
+        let note = || "bought at the club simul".to_owned();
+        assert_eq!(
+            set_note(collection_id, token_id, note()),
+            Err(NftError::Unauthorized)
+        );
+        runtime::set_caller(owner);
+        assert_eq!(set_note(collection_id, token_id, note()), Ok(()));
+        assert_eq!(my_note(collection_id, token_id), Some(note()));
+        runtime::set_caller(principal(1));
+        assert_eq!(my_note(collection_id, token_id), None);
+        runtime::set_caller(owner);
+        transfer_from_to(collection_id, token_id, owner, principal(3), None).unwrap();
+        runtime::set_caller(principal(3));
+        assert_eq!(my_note(collection_id, token_id), None);
+    }
+
+    #[test]
+    fn health_reports_uptime_and_txid() {
+        runtime::set_time(100);
+        init();
+        minted(principal(1), principal(2));
+        runtime::set_time(250);
+        let status = health();
+        assert!(status.ok);
+        assert_eq!(status.num_collections, 1);
+        assert_eq!(status.txid, current_txid());
+        assert_eq!(status.uptime_since_last_upgrade_ns, 150);
+        assert_eq!(version(), env!("CARGO_PKG_VERSION"));
+    }
+
+    #[test]
+    fn delegates_burn_only_when_the_policy_allows() {
+        let owner = principal(2);
+        let spender = principal(3);
+        let operator = principal(4);
+        let (collection_id, token_id) = minted(principal(1), owner);
+        let other_token = mint(collection_id, owner, vec![], vec![], None).unwrap();
+        runtime::set_caller(owner);
+        approve(collection_id, token_id, spender, None).unwrap();
+        set_approval_for_all(collection_id, operator, true).unwrap();
+        runtime::set_caller(spender);
+        assert_eq!(burn(collection_id, token_id), Err(NftError::Unauthorized));
+        runtime::set_caller(principal(1));
+        assert_eq!(
+            set_burn_policy(collection_id, BurnPolicy::OwnerOrDelegate),
+            Ok(())
+        );
+        runtime::set_caller(spender);
+        assert!(burn(collection_id, token_id).is_ok());
+        runtime::set_caller(operator);
+        assert!(burn(collection_id, other_token).is_ok());
+        let tx = transactions_of_token(collection_id, other_token, 0, MAX_LIMIT)
+            .0
+            .pop()
+            .unwrap();
+        assert!(tx.op == TxOp::Burn);
+        assert_eq!(tx.from, Some(owner));
+        assert_eq!(tx.caller, Some(operator));
+    }
+
+    #[test]
+    fn burned_tokens_have_no_owner() {
+        let owner = principal(2);
+        let (collection_id, token_id) = minted(principal(1), owner);
+        runtime::set_caller(owner);
+        burn(collection_id, token_id).unwrap();
+        assert_eq!(burned_count(collection_id), 1);
+        assert_eq!(balance_of_user(collection_id, ANONYMOUS), 0);
+        assert_eq!(owner_of_nft(collection_id, token_id), None);
+        assert!(tokens_of_collection_paged(collection_id, None, 10)
+            .0
+            .is_empty());
+    }
+
+    #[test]
+    fn metadata_json_is_sorted_and_escaped() {
+        let (collection_id, _) = minted(principal(1), principal(2));
+        let part = MetadataPart {
+            purpose: MetadataPurpose::Rendered,
+            key_val_data: HashMap::from([
+                (
+                    "theme".to_owned(),
+                    MetadataValue::Text("\"mate\" in 2".to_owned()),
+                ),
+                ("elo".to_owned(), MetadataValue::Nat16(1500)),
+                ("board".to_owned(), MetadataValue::Blob(vec![1, 2, 3, 4])),
+            ]),
+            data: b"pgn".to_vec(),
+        };
+        let token_id = mint(collection_id, principal(2), vec![part], vec![9; 8], None).unwrap();
+        assert_eq!(
+            token_metadata_json(collection_id, token_id).unwrap(),
+            format!(
+                "{{\"collection_id\":{collection_id},\"metadata\":[{{\"data\":\"cGdu\",\
+                 \"key_val_data\":{{\"board\":\"AQIDBA==\",\"elo\":1500,\
+                 \"theme\":\"\\\"mate\\\" in 2\"}},\"purpose\":\"Rendered\"}}],\
+                 \"token_id\":{token_id}}}"
+            )
+        );
+        assert_eq!(token_metadata_json(collection_id, token_id + 1), None);
+    }
+
+    #[test]
+    fn minters_can_finalize_their_uploads() {
+        let custodian = principal(1);
+        let minter = principal(5);
+        let (collection_id, _) = minted(custodian, principal(2));
+        assert_eq!(grant_role(collection_id, minter, Role::Minter), Ok(true));
+        runtime::set_caller(minter);
+        let upload_id = begin_upload(collection_id).unwrap();
+        upload_chunk(upload_id, vec![7; 16]).unwrap();
+        let token_id = finalize_mint(upload_id, principal(3), vec![]).unwrap();
+        assert_eq!(owner_of_nft(collection_id, token_id), Some(principal(3)));
+    }
+
+    #[test]
+    fn failed_finalize_keeps_the_upload() {
+        let (collection_id, _) = minted(principal(1), principal(2));
+        assert_eq!(set_max_supply(collection_id, 1), Ok(()));
+        let upload_id = begin_upload(collection_id).unwrap();
+        upload_chunk(upload_id, vec![7; 16]).unwrap();
+        assert_eq!(
+            finalize_mint(upload_id, principal(3), vec![]),
+            Err(NftError::SupplyExhausted)
+        );
+        STATE.with(|state| {
+            assert_eq!(state.borrow().uploads[&upload_id].content, vec![7; 16]);
+        });
+        assert_eq!(set_max_supply(collection_id, 2), Ok(()));
+        let token_id = finalize_mint(upload_id, principal(3), vec![]).unwrap();
+        assert_eq!(owner_of_nft(collection_id, token_id), Some(principal(3)));
+        STATE.with(|state| assert!(!state.borrow().uploads.contains_key(&upload_id)));
+    }
+
+    #[test]
+    fn batch_transfers_fire_transfer_hooks() {
+        let owner = principal(2);
+        let (collection_id, first) = minted(principal(1), owner);
+        let second = mint(collection_id, owner, vec![], vec![], None).unwrap();
+        let indexer = Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 9, 1, 1]);
+        register_transfer_hook(collection_id, indexer, "on_transfer".to_owned()).unwrap();
+        runtime::set_caller(owner);
+        batch_transfer(collection_id, vec![first, second], owner, principal(3)).unwrap();
+        assert_eq!(
+            runtime::take_notified(),
+            vec![(indexer, "on_transfer".to_owned()); 2]
+        );
+    }
+
+    #[test]
+    fn canister_recipients_are_notified_on_accept() {
+        let owner = principal(2);
+        let (collection_id, token_id) = minted(principal(1), owner);
+        let escrow = Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 9, 1, 1]);
+        runtime::set_caller(owner);
+        offer_transfer(collection_id, token_id, escrow).unwrap();
+        runtime::set_caller(escrow);
+        accept_transfer(collection_id, token_id).unwrap();
+        assert_eq!(
+            runtime::take_notified(),
+            vec![(escrow, "on_nft_received".to_owned())]
+        );
+    }
+
+    #[test]
+    fn lone_custodians_cannot_weaken_multi_approval() {
+        let (first, second) = (principal(1), principal(2));
+        let (collection_id, token_id) = minted(first, principal(3));
+        insert_custodian_into_collection(collection_id, second).unwrap();
+        assert_eq!(set_transfer_approvals_required(collection_id, 2), Ok(true));
+
+        assert_eq!(set_transfer_approvals_required(collection_id, 1), Ok(false));
+        let pending_id = propose_transfer(collection_id, token_id, principal(4)).unwrap();
+        assert_eq!(approve_transfer(pending_id), Ok(None));
+        assert!(matches!(
+            remove_custodian_from_collection(collection_id, second),
+            Err(NftError::Other(_))
+        ));
+        assert!(is_custodian_of_collection(collection_id, second).unwrap());
+
+        runtime::set_caller(second);
+        assert_eq!(set_transfer_approvals_required(collection_id, 1), Ok(true));
+        assert_eq!(
+            remove_custodian_from_collection(collection_id, first),
+            Ok(true)
+        );
+    }
+
+    #[test]
+    fn lone_custodians_cannot_approve_themselves() {
+        let (first, second) = (principal(1), principal(2));
+        let owner = principal(3);
+        let (collection_id, token_id) = minted(first, owner);
+        insert_custodian_into_collection(collection_id, second).unwrap();
+        assert_eq!(set_transfer_approvals_required(collection_id, 2), Ok(true));
+        assert!(matches!(
+            approve(collection_id, token_id, first, None),
+            Err(NftError::Other(_))
+        ));
+        assert_eq!(get_approved(collection_id, token_id), None);
+        runtime::set_caller(owner);
+        assert!(approve(collection_id, token_id, first, None).is_ok());
+    }
+
+    #[test]
+    fn custodian_set_changes_need_enough_custodians() {
+        let (first, second) = (principal(1), principal(2));
+        let (collection_id, _) = minted(first, principal(3));
+        assert_eq!(
+            insert_custodian_into_collection(collection_id, second),
+            Ok(true)
+        );
+        assert_eq!(set_transfer_approvals_required(collection_id, 2), Ok(true));
+
+        let puppet = principal(4);
+        assert_eq!(
+            insert_custodian_into_collection(collection_id, puppet),
+            Ok(false)
+        );
+        assert!(!is_custodian_of_collection(collection_id, puppet).unwrap());
+        assert_eq!(
+            transfer_collection_ownership(collection_id, vec![first, puppet]),
+            Ok(false)
+        );
+        assert_eq!(custodians_of_collection(collection_id), vec![first, second]);
+
+        runtime::set_caller(second);
+        assert_eq!(
+            transfer_collection_ownership(collection_id, vec![puppet, first]),
+            Ok(true)
+        );
+        assert_eq!(custodians_of_collection(collection_id), vec![first, puppet]);
+        assert_eq!(
+            insert_custodian_into_collection(collection_id, second),
+            Err(NftError::Unauthorized)
+        );
+        runtime::set_caller(first);
+        assert_eq!(
+            insert_custodian_into_collection(collection_id, second),
+            Ok(false)
+        );
+        runtime::set_caller(puppet);
+        assert_eq!(
+            insert_custodian_into_collection(collection_id, second),
+            Ok(true)
+        );
+    }
+
+    #[test]
+    fn operator_no_ops_do_not_consume_txids() {
+        let owner = principal(2);
+        let (collection_id, _) = minted(principal(1), owner);
+        runtime::set_caller(owner);
+        assert_eq!(
+            set_approval_for_all(collection_id, owner, true),
+            Err(NftError::SelfApprove)
+        );
+        let txid = current_txid();
+        assert_eq!(
+            set_approval_for_all(collection_id, principal(3), false),
+            Ok(txid)
+        );
+        let granted = set_approval_for_all(collection_id, principal(3), true).unwrap();
+        assert_eq!(
+            set_approval_for_all(collection_id, principal(3), true),
+            Ok(granted)
+        );
+        assert_eq!(current_txid(), granted);
+    }
+
+    #[test]
+    fn tokens_vote_once_per_proposal() {
+        let (holder, other) = (principal(2), principal(3));
+        let (collection_id, token_id) = minted(principal(1), holder);
+        runtime::set_caller(holder);
+        create_proposal("upgrade".to_owned(), 60, 0, 5000).unwrap();
+        runtime::set_time(1);
+        runtime::set_caller(principal(1));
+        let late = mint(collection_id, other, vec![], vec![], None).unwrap();
+        runtime::set_caller(holder);
+        assert_eq!(vote(1, true), Ok(()));
+        transfer_from_to(collection_id, token_id, holder, other, None).unwrap();
+        runtime::set_caller(other);
+        assert!(matches!(vote(1, true), Err(NftError::Other(_))));
+        runtime::set_caller(principal(4));
+        assert_eq!(vote(1, false), Err(NftError::Unauthorized));
+        runtime::set_caller(other);
+        transfer_from_to(collection_id, late, other, principal(4), None).unwrap();
+        runtime::set_caller(principal(4));
+        assert_eq!(vote(1, false), Err(NftError::Unauthorized));
+        let proposal = proposal(1).unwrap();
+        assert_eq!((proposal.yes, proposal.no), (1, 0));
+        assert!(proposal.yes + proposal.no <= proposal.eligible_supply);
+    }
+
+    #[test]
+    fn only_holders_create_bounded_proposals() {
+        let holder = principal(2);
+        minted(principal(1), holder);
+        let create = |description: String| create_proposal(description, 60, 0, 5000);
+        assert_eq!(create("upgrade".to_owned()), Err(NftError::Unauthorized));
+        runtime::set_caller(ANONYMOUS);
+        assert_eq!(create("upgrade".to_owned()), Err(NftError::Unauthorized));
+        runtime::set_caller(holder);
+        assert!(matches!(
+            create("x".repeat(MAX_DESCRIPTION_LEN + 1)),
+            Err(NftError::Other(_))
+        ));
+        assert_eq!(create("upgrade".to_owned()), Ok(1));
+    }
+
+    #[test]
+    fn replacing_the_pgn_keeps_it_consistent_with_the_fen() {
+        let owner = principal(2);
+        let (collection_id, token_id) = minted(principal(1), owner);
+        runtime::set_caller(owner);
+        attach_pgn(collection_id, token_id, "1. e4 e5 *".to_owned()).unwrap();
+        let fen = "rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq e6 0 2";
+        assert_eq!(
+            set_final_position(collection_id, token_id, fen.to_owned()),
+            Ok(())
+        );
+        assert!(matches!(
+            attach_pgn(collection_id, token_id, "1. d4 d5 *".to_owned()),
+            Err(NftError::Other(_))
+        ));
+        assert_eq!(
+            pgn_of_nft(collection_id, token_id).as_deref(),
+            Some("1. e4 e5 *")
+        );
+    }
+
+    fn play(fen: &str, moves: &[&str]) -> Result<String, String> {
+        let mut position = chess::Position::from_fen(fen)?;
+        for san in moves {
+            position.play_san(san)?;
+        }
+        Ok(position.to_fen())
+    }
+
+    const START_FEN: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+    #[test]
+    fn castling_needs_safe_squares_and_its_rook() {
+        let attacked_f1 = "r3k2r/8/8/8/8/8/5r2/R3K2R w KQkq - 0 1";
+        assert!(play(attacked_f1, &["O-O"]).is_err());
+        assert_eq!(
+            play(attacked_f1, &["O-O-O"]).as_deref(),
+            Ok("r3k2r/8/8/8/8/8/5r2/2KR3R b kq - 1 1")
+        );
+        let bishop_takes_h1 = "r3k2r/1b6/8/8/8/8/8/R3K2R b KQkq - 0 1";
+        assert_eq!(
+            play(bishop_takes_h1, &["Bxh1"]).as_deref(),
+            Ok("r3k2r/8/8/8/8/8/8/R3K2b w Qkq - 0 2")
+        );
+        assert!(play(bishop_takes_h1, &["Bxh1", "O-O"]).is_err());
+    }
+
+    #[test]
+    fn en_passant_removes_the_passed_pawn() {
+        assert_eq!(
+            play(START_FEN, &["e4", "a6", "e5", "d5", "exd6"]).as_deref(),
+            Ok("rnbqkbnr/1pp1pppp/p2P4/8/8/8/PPPP1PPP/RNBQKBNR b KQkq - 0 3")
+        );
+        // the right lapses after one move
+        assert!(play(START_FEN, &["e4", "a6", "e5", "d5", "h3", "h6", "exd6"]).is_err());
+    }
+
+    #[test]
+    fn pawns_promote_only_on_the_last_rank() {
+        let fen = "8/P7/8/8/8/8/8/k6K w - - 0 1";
+        assert_eq!(
+            play(fen, &["a8=Q"]).as_deref(),
+            Ok("Q7/8/8/8/8/8/8/k6K b - - 0 1")
+        );
+        assert!(play(fen, &["a8"]).is_err());
+        assert!(play(fen, &["a8=K"]).is_err());
+        assert!(play(START_FEN, &["e4=Q"]).is_err());
+    }
+
+    #[test]
+    fn ambiguous_moves_need_disambiguation() {
+        let knights = ["d4", "a6", "Nf3", "a5"];
+        assert!(play(START_FEN, &[&knights[..], &["Nd2"]].concat()).is_err());
+        assert_eq!(
+            play(START_FEN, &[&knights[..], &["Nbd2"]].concat()).as_deref(),
+            Ok("rnbqkbnr/1ppppppp/8/p7/3P4/5N2/PPPNPPPP/R1BQKB1R b KQkq - 1 3")
+        );
+        let rooks = "4k3/8/8/8/8/4R3/8/4R2K w - - 0 1";
+        assert!(play(rooks, &["Re2"]).is_err());
+        assert_eq!(
+            play(rooks, &["R1e2"]).as_deref(),
+            Ok("4k3/8/8/8/8/4R3/4R3/7K b - - 1 1")
+        );
+    }
+
+    #[test]
+    fn pinned_pieces_cannot_expose_their_king() {
+        let pinned = "4k3/8/8/8/1b6/8/3N4/4K3 w - - 0 1";
+        assert!(play(pinned, &["Nf3"]).is_err());
+        assert!(play(pinned, &["Nb3"]).is_err());
+        assert!(play(pinned, &["Kf2"]).is_ok());
+    }
+
+    #[test]
+    fn malformed_fens_are_rejected() {
+        for fen in [
+            "rnbqkbnr/ppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+            "rnbqkbnr/ppppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkx - 0 1",
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KKq - 0 1",
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq e4 0 1",
+        ] {
+            assert!(chess::Position::from_fen(fen).is_err(), "{fen}");
+        }
+        assert!(chess::Position::from_fen(START_FEN).is_ok());
+    }
+
+    #[test]
+    fn pgn_syntax_errors_are_rejected() {
+        for pgn in [
+            "1. e4 Zz5 *",
+            "[Event \"open\" 1. e4 *",
+            "[Event \"open\"]] 1. e4 *",
+            "1. e4 {unfinished comment",
+            "1. e4 } e5 *",
+            "1. e4 (1. d4 d5 *",
+            "1. e4 ) e5 *",
+            "1. e4 e5 1-0 2. Nf3",
+        ] {
+            assert!(chess::parse_pgn(pgn).is_err(), "{pgn}");
+        }
+        let pgn = chess::parse_pgn(
+            "[Event \"The \\\"Immortal\\\" Game\"]\n1. e4 {best by test} e5 (1... c5) 2. Nf3 $1 *",
+        )
+        .unwrap();
+        assert_eq!(
+            pgn.tags,
+            vec![("Event".to_owned(), "The \"Immortal\" Game".to_owned())]
+        );
+        assert_eq!(pgn.moves, vec!["e4", "e5", "Nf3"]);
+    }
+
+    #[test]
+    fn token_history_pages_through_the_ledger() {
+        let owner = principal(2);
+        let (collection_id, token_id) = minted(principal(1), owner);
+        let other = mint(collection_id, owner, vec![], vec![], None).unwrap();
+        runtime::set_caller(owner);
+        transfer_from_to(collection_id, token_id, owner, principal(3), None).unwrap();
+        let (page, next) = transactions_of_token(collection_id, token_id, 0, 1);
+        assert_eq!(page.len(), 1);
+        assert!(page[0].op == TxOp::Mint);
+        let (page, next) = transactions_of_token(collection_id, token_id, next.unwrap(), 1);
+        assert!(page[0].op == TxOp::Transfer);
+        assert_eq!(next, None);
+        let (page, next) = transactions_of_principal(principal(3), 0, MAX_LIMIT);
+        assert_eq!((page.len(), next), (1, None));
+        assert_eq!(
+            transactions_of_token(collection_id, other, 0, MAX_LIMIT)
+                .0
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn no_op_approvals_report_the_txid_in_place() {
+        let (custodian, owner) = (principal(1), principal(2));
+        let (collection_id, token_id) = minted(custodian, owner);
+        runtime::set_caller(owner);
+        let approved = approve(collection_id, token_id, principal(3), None).unwrap();
+        let granted = set_approval_for_all(collection_id, principal(4), true).unwrap();
+        let cleared = set_approval_for_all(collection_id, ANONYMOUS, false).unwrap();
+        runtime::set_caller(custodian);
+        mint(collection_id, owner, vec![], vec![], None).unwrap();
+        runtime::set_caller(owner);
+        assert!(granted > approved && cleared > granted);
+        assert_eq!(
+            approve(collection_id, token_id, principal(3), None),
+            Ok(approved)
+        );
+        assert_eq!(
+            set_approval_for_all(collection_id, principal(4), false),
+            Ok(cleared)
+        );
+        assert_eq!(
+            set_approval_for_all(collection_id, ANONYMOUS, false),
+            Ok(cleared)
+        );
+    }
+
+    #[test]
+    fn deposits_accept_every_attached_cycle() {
+        assert_eq!(deposit_cycles(), 0);
+        runtime::set_cycles_available(5_000);
+        assert_eq!(deposit_cycles(), 5_000);
+        assert_eq!(runtime::msg_cycles_available(), 0);
+        assert_eq!(deposit_cycles(), 0);
+    }
+
+    #[test]
+    fn content_limits_stay_within_the_upload_size() {
+        let (collection_id, _) = minted(principal(1), principal(2));
+        for max_bytes in [0, MAX_UPLOAD_SIZE as u64 + 1] {
+            assert!(matches!(
+                set_max_content_bytes(collection_id, Some(max_bytes)),
+                Err(NftError::Other(_))
+            ));
+        }
+        let max_bytes = MAX_UPLOAD_SIZE as u64;
+        assert_eq!(
+            set_max_content_bytes(collection_id, Some(max_bytes)),
+            Ok(())
+        );
+        assert_eq!(max_content_bytes(collection_id), Some(max_bytes));
+        runtime::set_caller(principal(2));
+        assert_eq!(
+            set_max_content_bytes(collection_id, Some(1)),
+            Err(NftError::Unauthorized)
+        );
+    }
+}
@@ -0,0 +1,115 @@
+//! The parts of the system API the canister logic depends on, swapped for
+//! settable values in unit tests where the real system API is unavailable.
+
+#[cfg(not(test))]
+pub use ic_cdk::api::{caller, canister_balance, id, is_controller, time};
+
+#[cfg(not(test))]
+pub use ic_cdk::api::call::{msg_cycles_accept, msg_cycles_available, notify};
+
+#[cfg(not(test))]
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+#[cfg(not(test))]
+pub fn heap_bytes() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    return core::arch::wasm32::memory_size(0) as u64 * WASM_PAGE_SIZE;
+    #[cfg(not(target_arch = "wasm32"))]
+    return 0;
+}
+
+#[cfg(not(test))]
+pub fn stable_bytes() -> u64 {
+    ic_cdk::api::stable::stable64_size() * WASM_PAGE_SIZE
+}
+
+#[cfg(test)]
+pub use mock::*;
+
+#[cfg(test)]
+mod mock {
+    use std::cell::{Cell, RefCell};
+
+    use candid::utils::ArgumentEncoder;
+    use candid::Principal;
+    use ic_cdk::api::call::RejectionCode;
+
+    thread_local! {
+        static CALLER: Cell<Principal> = const { Cell::new(Principal::anonymous()) };
+        static TIME: Cell<u64> = const { Cell::new(0) };
+        static BALANCE: Cell<u64> = const { Cell::new(u64::MAX) };
+        static CONTROLLER: Cell<Option<Principal>> = const { Cell::new(None) };
+        static CYCLES: Cell<u64> = const { Cell::new(0) };
+        static NOTIFIED: RefCell<Vec<(Principal, String)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    pub fn caller() -> Principal {
+        CALLER.with(Cell::get)
+    }
+
+    pub fn time() -> u64 {
+        TIME.with(Cell::get)
+    }
+
+    pub fn canister_balance() -> u64 {
+        BALANCE.with(Cell::get)
+    }
+
+    pub fn id() -> Principal {
+        Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 1, 1, 1])
+    }
+
+    pub fn is_controller(principal: &Principal) -> bool {
+        CONTROLLER.with(Cell::get) == Some(*principal)
+    }
+
+    /// Calls in unit tests carry no cycles unless `set_cycles_available`
+    /// says otherwise.
+    pub fn msg_cycles_available() -> u64 {
+        CYCLES.with(Cell::get)
+    }
+
+    pub fn msg_cycles_accept(max_amount: u64) -> u64 {
+        let accepted = msg_cycles_available().min(max_amount);
+        CYCLES.with(|cell| cell.set(cell.get() - accepted));
+        accepted
+    }
+
+    /// Records the callee and method instead of sending anything.
+    pub fn notify<T: ArgumentEncoder>(
+        id: Principal,
+        method: &str,
+        _args: T,
+    ) -> Result<(), RejectionCode> {
+        NOTIFIED.with(|notified| notified.borrow_mut().push((id, method.to_owned())));
+        Ok(())
+    }
+
+    pub fn heap_bytes() -> u64 {
+        0
+    }
+
+    pub fn stable_bytes() -> u64 {
+        0
+    }
+
+    pub fn set_caller(caller: Principal) {
+        CALLER.with(|cell| cell.set(caller))
+    }
+
+    pub fn set_time(time: u64) {
+        TIME.with(|cell| cell.set(time))
+    }
+
+    pub fn set_controller(controller: Principal) {
+        CONTROLLER.with(|cell| cell.set(Some(controller)))
+    }
+
+    pub fn set_cycles_available(cycles: u64) {
+        CYCLES.with(|cell| cell.set(cycles))
+    }
+
+    pub fn take_notified() -> Vec<(Principal, String)> {
+        NOTIFIED.with(|notified| notified.take())
+    }
+}